@@ -29,6 +29,18 @@ fn read(c: &mut Criterion) {
     });
 }
 
+fn write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    group.sample_size(10);
+
+    let alignments = gam::parse_from_file("data/example.gam").unwrap();
+    for threads in [1, 4] {
+        group.bench_function(format!("gam {} threads", threads), |b| {
+            b.iter(|| gam::write(&alignments, sink(), threads).unwrap())
+        });
+    }
+}
+
 fn vg_read(c: &mut Criterion) {
     let mut group = c.benchmark_group("vg read");
     group.sample_size(10);
@@ -55,5 +67,5 @@ fn vg_read(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, read, vg_read);
+criterion_group!(benches, read, write, vg_read);
 criterion_main!(benches);
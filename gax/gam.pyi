@@ -2,4 +2,4 @@ from gax.definitions import Alignment
 
 
 def parse(path: str) -> list[Alignment]: ...
-def write(value: list[Alignment], file_name: str) -> None: ...
+def write(value: list[Alignment], file_name: str, threads: int = 1) -> None: ...
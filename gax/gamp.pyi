@@ -2,4 +2,4 @@ from gax.definitions import MultipathAlignment
 
 
 def parse(path: str) -> list[MultipathAlignment]: ...
-def write(value: list[MultipathAlignment], file_name: str) -> None: ...
+def write(value: list[MultipathAlignment], file_name: str, threads: int = 1) -> None: ...
@@ -0,0 +1,659 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression, Crc};
+use std::{
+    collections::VecDeque,
+    io::{self, prelude::*, ErrorKind, SeekFrom},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+/**
+ * Maximum amount of uncompressed data in a block, same as htslib
+ */
+pub(crate) const BLOCK_SIZE: usize = 0xff00;
+const MAX_BLOCK_SIZE: usize = 0x10000;
+
+// gzip header with the BGZF "BC" extra field, the last two bytes hold the block size
+const HEADER: [u8; 16] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+];
+pub(crate) const HEADER_SIZE: usize = HEADER.len() + 2;
+
+// Empty block marking the end of the file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0,
+    0, 0,
+];
+
+/**
+ * Position in a BGZF file: the offset of a block in the compressed file
+ * and the offset inside the uncompressed block, packed as in htslib.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct VirtualOffset(u64);
+
+impl VirtualOffset {
+    pub fn new(block_offset: u64, in_block_offset: u16) -> Self {
+        Self(block_offset << 16 | in_block_offset as u64)
+    }
+
+    pub fn block_offset(&self) -> u64 {
+        self.0 >> 16
+    }
+
+    pub fn in_block_offset(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl From<u64> for VirtualOffset {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<VirtualOffset> for u64 {
+    fn from(value: VirtualOffset) -> Self {
+        value.0
+    }
+}
+
+/**
+ * Check if `data` starts with a BGZF block header
+ */
+pub(crate) fn is_bgzf(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE
+        && data[..4] == HEADER[..4]
+        // The BC subfield must come first in the extra field
+        && u16::from_le_bytes([data[10], data[11]]) >= 6
+        && data[12..16] == HEADER[12..16]
+}
+
+/**
+ * Reader of BGZF files, keeping track of the virtual offset of the data read.
+ * With worker threads, the blocks ahead of the current one are inflated in the background.
+ */
+pub(crate) struct Reader<R: BufRead> {
+    inner: R,
+    block: Vec<u8>,
+    position: usize,
+    // Offset of the current block in the compressed file
+    block_offset: u64,
+    // Offset of the next block in the compressed file
+    next_block_offset: u64,
+    cdata: Vec<u8>,
+    pool: Option<ThreadPool>,
+    // Blocks being inflated by the pool, in file order, with their compressed size
+    pending: VecDeque<(mpsc::Receiver<io::Result<Vec<u8>>>, usize)>,
+    // Error met while reading ahead, returned once the pending blocks are read
+    error: Option<io::Error>,
+}
+
+impl<R: BufRead> Reader<R> {
+    /**
+     * Reader inflating blocks with `threads` worker threads, 0 or 1 inflates them when read
+     */
+    pub(crate) fn with_threads(inner: R, threads: usize) -> Self {
+        Self {
+            inner,
+            block: vec![],
+            position: 0,
+            block_offset: 0,
+            next_block_offset: 0,
+            cdata: vec![],
+            pool: (threads > 1).then(|| ThreadPool::new(threads)),
+            pending: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /**
+     * Virtual offset of the next byte to be read
+     */
+    pub(crate) fn virtual_position(&self) -> VirtualOffset {
+        if self.position == self.block.len() {
+            VirtualOffset::new(self.next_block_offset, 0)
+        } else {
+            VirtualOffset::new(self.block_offset, self.position as u16)
+        }
+    }
+
+    /**
+     * Read and decompress the next block, returning false at the end of the file
+     */
+    fn read_block(&mut self) -> io::Result<bool> {
+        let block_size = if let Some(pool) = &self.pool {
+            // Keep the workers busy with the blocks ahead
+            while self.error.is_none() && self.pending.len() < 2 * pool.threads() {
+                let mut cdata = vec![];
+                let block_size = match read_cdata(&mut self.inner, &mut cdata) {
+                    Ok(Some(block_size)) => block_size,
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.error = Some(e);
+                        break;
+                    }
+                };
+                let block = pool.spawn(move || {
+                    let mut block = vec![];
+                    inflate_block(&cdata, &mut block)?;
+                    Ok(block)
+                });
+                self.pending.push_back((block, block_size));
+            }
+            let Some((block, block_size)) = self.pending.pop_front() else {
+                return match self.error.take() {
+                    Some(e) => Err(e),
+                    None => Ok(false),
+                };
+            };
+            self.block = block.recv().map_err(|_| worker_stopped())??;
+            block_size
+        } else {
+            let Some(block_size) = read_cdata(&mut self.inner, &mut self.cdata)? else {
+                return Ok(false);
+            };
+            inflate_block(&self.cdata, &mut self.block)?;
+            block_size
+        };
+
+        self.block_offset = self.next_block_offset;
+        self.next_block_offset += block_size as u64;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+/**
+ * Read the next block of `inner` without its BGZF header in `cdata`,
+ * returning the size of the whole block or `None` at the end of the file
+ */
+fn read_cdata(inner: &mut impl BufRead, cdata: &mut Vec<u8>) -> io::Result<Option<usize>> {
+    if inner.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut header = [0; HEADER_SIZE];
+    inner.read_exact(&mut header)?;
+    if !is_bgzf(&header) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid BGZF block header",
+        ));
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let block_size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+    // The gzip header is 12 bytes followed by the extra subfields
+    let header_size = 12 + xlen;
+    if block_size < header_size + 8 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid BGZF block size",
+        ));
+    }
+    // Skip the other extra subfields, if any
+    io::copy(&mut inner.take(xlen as u64 - 6), &mut io::sink())?;
+
+    cdata.resize(block_size - header_size, 0);
+    inner.read_exact(cdata)?;
+    Ok(Some(block_size))
+}
+
+/**
+ * Decompress the compressed data and footer of a block in `block`, checking its CRC
+ */
+fn inflate_block(cdata: &[u8], block: &mut Vec<u8>) -> io::Result<()> {
+    let (cdata, footer) = cdata.split_at(cdata.len() - 8);
+    let crc = u32::from_le_bytes(footer[..4].try_into().unwrap());
+    let size = u32::from_le_bytes(footer[4..].try_into().unwrap()) as usize;
+    if size > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid BGZF block size",
+        ));
+    }
+
+    block.clear();
+    block.reserve(size);
+    // One more byte than announced is enough to detect a wrong size
+    DeflateDecoder::new(cdata)
+        .take(size as u64 + 1)
+        .read_to_end(block)?;
+    let mut block_crc = Crc::new();
+    block_crc.update(block);
+    if block.len() != size || block_crc.sum() != crc {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "corrupted BGZF block",
+        ));
+    }
+    Ok(())
+}
+
+impl<R: BufRead + Seek> Reader<R> {
+    /**
+     * Move to the given virtual offset, so that the next byte read is the one at `offset`
+     */
+    pub(crate) fn seek_virtual(&mut self, offset: VirtualOffset) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset.block_offset()))?;
+        // Blocks read ahead are dropped, workers will discard them
+        self.pending.clear();
+        self.error = None;
+        self.block.clear();
+        self.position = 0;
+        self.next_block_offset = offset.block_offset();
+        let in_block_offset = offset.in_block_offset() as usize;
+        if in_block_offset > 0 {
+            if !self.read_block()? || in_block_offset > self.block.len() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid virtual offset",
+                ));
+            }
+            self.position = in_block_offset;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // vg writes empty blocks in the middle of files, skip them
+        while self.position == self.block.len() {
+            if !self.read_block()? {
+                break;
+            }
+        }
+        Ok(&self.block[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.block.len());
+    }
+}
+
+/**
+ * Writer of BGZF files: data is split in blocks of at most `BLOCK_SIZE` bytes,
+ * each one compressed in its own gzip member.
+ * With worker threads, blocks are compressed in the background and written in order.
+ */
+pub(crate) struct Writer<W: Write> {
+    inner: Option<W>,
+    buffer: Vec<u8>,
+    level: Compression,
+    pool: Option<ThreadPool>,
+    // Blocks being compressed by the pool, in file order, one or more per job
+    pending: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+}
+
+impl<W: Write> Writer<W> {
+    /**
+     * Writer compressing blocks with `threads` worker threads, 0 or 1 compresses them when written
+     */
+    pub(crate) fn with_threads(inner: W, level: Compression, threads: usize) -> Self {
+        Self {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            level,
+            pool: (threads > 1).then(|| ThreadPool::new(threads)),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /**
+     * Compress and write the pending data, even if the block is not full.
+     * With worker threads, the block may be written later, `flush` waits for all of them.
+     */
+    pub(crate) fn flush_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let Some(pool) = &self.pool else {
+            let block = compress_block(&self.buffer, self.level)?;
+            self.inner
+                .as_mut()
+                .expect("writer already finished")
+                .write_all(&block)?;
+            self.buffer.clear();
+            return Ok(());
+        };
+
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(BLOCK_SIZE));
+        let level = self.level;
+        let block = pool.spawn(move || compress_block(&data, level));
+        self.push_pending(block)
+    }
+
+    /**
+     * Write the data returned by `data` after the pending data, in blocks of its own.
+     * With worker threads, `data` is produced and compressed in the background.
+     */
+    pub(crate) fn write_blocks(
+        &mut self,
+        data: impl FnOnce() -> io::Result<Vec<u8>> + Send + 'static,
+    ) -> io::Result<()> {
+        self.flush_block()?;
+        let Some(pool) = &self.pool else {
+            self.write_all(&data()?)?;
+            return self.flush_block();
+        };
+
+        let level = self.level;
+        let blocks = pool.spawn(move || {
+            let mut blocks = vec![];
+            for data in data()?.chunks(BLOCK_SIZE) {
+                blocks.extend_from_slice(&compress_block(data, level)?);
+            }
+            Ok(blocks)
+        });
+        self.push_pending(blocks)
+    }
+
+    /**
+     * Number of worker threads, 1 when blocks are compressed in the calling thread
+     */
+    pub(crate) fn threads(&self) -> usize {
+        self.pool.as_ref().map_or(1, ThreadPool::threads)
+    }
+
+    fn push_pending(&mut self, blocks: mpsc::Receiver<io::Result<Vec<u8>>>) -> io::Result<()> {
+        self.pending.push_back(blocks);
+        // Bound the memory used by blocks waiting to be written
+        let max_pending = 2 * self.threads();
+        while self.pending.len() > max_pending {
+            self.write_pending_block()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Wait for the oldest blocks compressed by the pool and write them
+     */
+    fn write_pending_block(&mut self) -> io::Result<()> {
+        let Some(block) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        let block = block.recv().map_err(|_| worker_stopped())??;
+        self.inner
+            .as_mut()
+            .expect("writer already finished")
+            .write_all(&block)
+    }
+
+    fn write_pending_blocks(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            self.write_pending_block()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Write the pending data and the EOF marker, returning the underlying writer
+     */
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().expect("writer already finished"))
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.write_pending_blocks()?;
+        let inner = self.inner.as_mut().expect("writer already finished");
+        inner.write_all(&EOF_BLOCK)?;
+        inner.flush()
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.write_pending_blocks()?;
+        self.inner
+            .as_mut()
+            .expect("writer already finished")
+            .flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+/**
+ * Compress `data` in a single BGZF block
+ */
+pub(crate) fn compress_block(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(MAX_BLOCK_SIZE);
+    block.extend_from_slice(&HEADER);
+    // Block size, filled in once the data is compressed
+    block.extend_from_slice(&[0, 0]);
+
+    let mut encoder = DeflateEncoder::new(block, level);
+    encoder.write_all(data)?;
+    let mut block = encoder.finish()?;
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    if block.len() > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "BGZF block does not fit in 64 KiB",
+        ));
+    }
+    let block_size = (block.len() - 1) as u16;
+    block[HEADER_SIZE - 2..HEADER_SIZE].copy_from_slice(&block_size.to_le_bytes());
+    Ok(block)
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/**
+ * Fixed set of worker threads running jobs in the background
+ */
+struct ThreadPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // The lock is released before running the job
+                    let job = receiver.lock().expect("worker thread panicked").recv();
+                    match job {
+                        Ok(job) => job(),
+                        // The pool was dropped
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /**
+     * Run `f` on a worker, its result is sent to the returned receiver
+     */
+    fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> mpsc::Receiver<T> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let job = Box::new(move || {
+            // The receiver is gone if the result is no longer needed
+            let _ = sender.send(f());
+        });
+        if let Some(jobs) = &self.jobs {
+            // If all workers are gone, the receiver reports it
+            let _ = jobs.send(job);
+        }
+        receiver
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_stopped() -> io::Error {
+    io::Error::other("BGZF worker thread stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Cursor;
+
+    #[test]
+    fn bgzf_write() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 1);
+        writer.write_all(&data)?;
+        let compressed = writer.finish()?;
+
+        // Walk the blocks using their BSIZE field
+        let mut offset = 0;
+        let mut blocks = 0;
+        while offset < compressed.len() {
+            assert_eq!(compressed[offset..offset + HEADER.len()], HEADER);
+            let size = u16::from_le_bytes([compressed[offset + 16], compressed[offset + 17]]);
+            offset += size as usize + 1;
+            blocks += 1;
+        }
+        assert_eq!(offset, compressed.len());
+        assert_eq!(blocks, data.len().div_ceil(BLOCK_SIZE) + 1);
+        assert!(compressed.ends_with(&EOF_BLOCK));
+
+        let mut decompressed = vec![];
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, data);
+        Ok(())
+    }
+
+    #[test]
+    fn bgzf_seek() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 1);
+        writer.write_all(&data)?;
+        let compressed = writer.finish()?;
+
+        let mut reader = Reader::with_threads(Cursor::new(compressed), 1);
+        let mut buf = vec![0; 100_000];
+        reader.read_exact(&mut buf)?;
+        let offset = reader.virtual_position();
+        assert_eq!(offset.in_block_offset() as usize, 100_000 - BLOCK_SIZE);
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, data[100_000..]);
+
+        reader.seek_virtual(offset)?;
+        rest.clear();
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, data[100_000..]);
+        Ok(())
+    }
+
+    #[test]
+    fn bgzf_threads() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 1);
+        writer.write_all(&data)?;
+        let expected = writer.finish()?;
+
+        // Same blocks, in the same order
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 4);
+        writer.write_all(&data)?;
+        let compressed = writer.finish()?;
+        assert_eq!(compressed, expected);
+
+        let mut reader = Reader::with_threads(Cursor::new(compressed), 4);
+        let mut buf = vec![0; 300_000];
+        reader.read_exact(&mut buf)?;
+        let offset = reader.virtual_position();
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, data[300_000..]);
+
+        reader.seek_virtual(offset)?;
+        rest.clear();
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, data[300_000..]);
+        Ok(())
+    }
+
+    #[test]
+    fn bgzf_threads_error() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 1);
+        writer.write_all(&data)?;
+        let mut compressed = writer.finish()?;
+        // A corrupted block header after the data, read ahead of the blocks before it
+        compressed.truncate(compressed.len() - EOF_BLOCK.len());
+        compressed.extend([0; HEADER_SIZE]);
+
+        let mut reader = Reader::with_threads(Cursor::new(compressed), 4);
+        let mut decompressed = vec![];
+        let e = reader.read_to_end(&mut decompressed).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(decompressed, data);
+        Ok(())
+    }
+
+    #[test]
+    fn bgzf_block_size() -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = Writer::with_threads(vec![], Compression::default(), 1);
+        writer.write_all(b"ACGT")?;
+        let mut compressed = writer.finish()?;
+        // ISIZE of the first block, the last 4 bytes of its footer
+        let size = u16::from_le_bytes([compressed[16], compressed[17]]) as usize + 1;
+        compressed[size - 4..size].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = Reader::with_threads(Cursor::new(compressed), 1);
+        let e = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -51,7 +51,7 @@ fn write(gafs: Vec<PyObject>, file_name: &str) -> PyResult<()> {
         let records = gafs
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<gaf::GafRecord>(py) })
-            .collect::<PyResult<_>>()?;
+            .collect::<PyResult<Vec<_>>>()?;
         gaf::write_to_file(&records, file_name)?;
         Ok(())
     })
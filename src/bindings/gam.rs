@@ -315,14 +315,14 @@ fn parse(file_name: &str) -> PyResult<Vec<PyObject>> {
     })
 }
 
-#[pyfunction(name = "write")]
-fn write(gams: Vec<PyObject>, file_name: &str) -> PyResult<()> {
+#[pyfunction(name = "write", threads = "1")]
+fn write(gams: Vec<PyObject>, file_name: &str, threads: usize) -> PyResult<()> {
     Python::with_gil(|py| -> PyResult<_> {
         let records = gams
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<vg::Alignment>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        gam::write_to_file(&records, file_name)?;
+        gam::write_to_file(&records, file_name, threads)?;
         Ok(())
     })
 }
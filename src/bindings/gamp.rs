@@ -99,14 +99,14 @@ fn parse(file_name: &str) -> PyResult<Vec<PyObject>> {
     })
 }
 
-#[pyfunction]
-fn write(gamp: Vec<PyObject>, file_name: &str) -> PyResult<()> {
+#[pyfunction(threads = "1")]
+fn write(gamp: Vec<PyObject>, file_name: &str, threads: usize) -> PyResult<()> {
     Python::with_gil(|py| -> PyResult<_> {
         let records = gamp
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<vg::MultipathAlignment>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        gamp::write_to_file(&records, file_name)?;
+        gamp::write_to_file(&records, file_name, threads)?;
         Ok(())
     })
 }
@@ -1,14 +1,32 @@
-use gfa::{gfa::GFA, parser::GFAParser};
-use pyo3::{pyclass, pyfunction, PyResult};
+use crate::{graph::GraphIndex, translation::Translation};
+use gfa::{
+    gfa::GFA,
+    optfields::OptionalFields,
+    parser::{GFAParser, ParseError},
+};
+use pyo3::{pyclass, pyfunction, PyErr, PyResult};
 
 #[pyclass(name = "Graph")]
 pub struct GFAWrapper {
-    pub graph: GFA<usize, ()>,
+    pub graph: GraphIndex,
 }
 
 #[pyfunction]
-pub(crate) fn load_graph(path: &str) -> PyResult<GFAWrapper> {
-    Ok(GFAWrapper {
-        graph: GFAParser::new().parse_file(path).unwrap(),
-    })
+pub(crate) fn load_graph(path: &str, translation: Option<&str>) -> PyResult<GFAWrapper> {
+    // Optional fields are kept for the SN and SO tags of rGFA graphs,
+    // segment names that are not numbers are translated to node IDs
+    let graph: GFA<Vec<u8>, OptionalFields> =
+        GFAParser::new().parse_file(path).map_err(|e| -> PyErr {
+            match e {
+                ParseError::IOError(e) => e.into(),
+                e => PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e)),
+            }
+        })?;
+    let graph = match translation {
+        Some(translation) => {
+            GraphIndex::with_translation(&graph, Translation::parse_from_file(translation)?)?
+        }
+        None => GraphIndex::new(&graph)?,
+    };
+    Ok(GFAWrapper { graph })
 }
@@ -1,7 +1,7 @@
 use crate::{
     framing,
     gaf::{GafError, GafRecord},
-    vg,
+    vg, ConversionError, TranslationError,
 };
 use graph::GFAWrapper;
 use pyo3::prelude::*;
@@ -23,7 +23,44 @@ impl From<framing::FramingError> for PyErr {
             framing::FramingError::ProstEncode(e) => {
                 PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
             }
-            framing::FramingError::InvalidTypeTag(..) => {
+            framing::FramingError::InvalidTypeTag(..)
+            | framing::FramingError::NotBgzf
+            | framing::FramingError::InvalidIndex(_)
+            | framing::FramingError::MessageTooLong(_) => {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
+            }
+        }
+    }
+}
+
+impl From<ConversionError> for PyErr {
+    fn from(e: ConversionError) -> Self {
+        match e {
+            ConversionError::ParseInt(e) => e.into(),
+            ConversionError::ParseFloat(e) => e.into(),
+            ConversionError::Gaf(e) => e.into(),
+            ConversionError::Translation(e) => e.into(),
+            ConversionError::MissingPosition
+            | ConversionError::UnresolvedStableStep(_)
+            | ConversionError::MissingNode(_)
+            | ConversionError::MissingSegment(_)
+            | ConversionError::DuplicateSegment(_)
+            | ConversionError::DuplicateNode(_)
+            | ConversionError::IncompleteSegment(_)
+            | ConversionError::OffsetOutOfRange { .. } => {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
+            }
+        }
+    }
+}
+
+impl From<TranslationError> for PyErr {
+    fn from(e: TranslationError) -> Self {
+        match e {
+            TranslationError::Io(e) => e.into(),
+            TranslationError::InvalidLine(_)
+            | TranslationError::DuplicateSegment(_)
+            | TranslationError::DuplicateNode(_) => {
                 PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
             }
         }
@@ -34,14 +71,22 @@ impl From<GafError> for PyErr {
     fn from(e: GafError) -> Self {
         match e {
             GafError::Io(e) => e.into(),
-            GafError::ParseInt(e) => e.into(),
+            GafError::Framing(e) => e.into(),
             GafError::MissingStart => {
                 PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
             }
             GafError::MissingEnd => {
                 PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
             }
-            GafError::MissingToken => {
+            GafError::MissingToken(_)
+            | GafError::InvalidNumber(_)
+            | GafError::InvalidStrand(_)
+            | GafError::InvalidInterval(_)
+            | GafError::InvalidTag(_)
+            | GafError::DuplicateTag(_)
+            | GafError::InvalidCigar(_)
+            | GafError::CigarMismatch(_)
+            | GafError::InvalidCs(_) => {
                 PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Error: {}", e))
             }
         }
@@ -55,7 +100,7 @@ pub fn convert_gam_to_gaf(value: Vec<PyObject>, graph: &GFAWrapper) -> PyResult<
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<vg::Alignment>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        let gaf = crate::convert_gam_to_gaf(&gam, &graph.graph);
+        let gaf = crate::convert_gam_to_gaf(&gam, &graph.graph)?;
         let py_gaf = gaf.iter().map(|o| o.clone().into_py(py)).collect();
         Ok(py_gaf)
     })
@@ -68,7 +113,7 @@ pub fn convert_gaf_to_gam(value: Vec<PyObject>, graph: &GFAWrapper) -> PyResult<
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<GafRecord>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        let gam = crate::convert_gaf_to_gam(&gaf, &graph.graph);
+        let gam = crate::convert_gaf_to_gam(&gaf, &graph.graph)?;
         let py_gam = gam.iter().map(|o| o.clone().into_py(py)).collect();
         Ok(py_gam)
     })
@@ -81,7 +126,7 @@ pub fn convert_gam_to_gamp(value: Vec<PyObject>) -> PyResult<Vec<PyObject>> {
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<vg::Alignment>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        let gamp: Vec<_> = gam.into();
+        let gamp: Vec<vg::MultipathAlignment> = gam.into_iter().map(Into::into).collect();
         let py_gamp = gamp.iter().map(|o| o.clone().into_py(py)).collect();
         Ok(py_gamp)
     })
@@ -94,7 +139,7 @@ pub fn convert_gamp_to_gam(value: Vec<PyObject>) -> PyResult<Vec<PyObject>> {
             .iter()
             .map(|o| -> PyResult<_> { o.extract::<vg::MultipathAlignment>(py) })
             .collect::<PyResult<Vec<_>>>()?;
-        let gam: Vec<_> = gam.into();
+        let gam: Vec<vg::Alignment> = gam.into_iter().map(Into::into).collect();
         let py_gam = gam.iter().map(|o| o.clone().into_py(py)).collect();
         Ok(py_gam)
     })
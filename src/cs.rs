@@ -0,0 +1,257 @@
+use crate::{
+    gaf::{GafError, GafRecord, Location},
+    graph::{GFAExt, GraphIndex},
+    ConversionError,
+};
+use std::fmt;
+
+/**
+ * Operation of a cs string, as described here: https://github.com/lh3/minimap2#cs
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsOp {
+    Match(usize),      // :10 identical bases, short form
+    Identical(String), // =ACGT identical bases, long form
+    // *ag base of the path then base of the read
+    Substitution {
+        target: char,
+        query: char,
+    },
+    Insertion(String), // +acg bases of the read only
+    Deletion(String),  // -acg bases of the path only
+    // ~gt120ag splice signals around the length of the intron, which includes them
+    Intron {
+        donor: String,
+        length: usize,
+        acceptor: String,
+    },
+}
+
+impl CsOp {
+    /**
+     * Number of bases of the read covered by the operation
+     */
+    pub fn query_length(&self) -> usize {
+        match self {
+            CsOp::Match(length) => *length,
+            CsOp::Identical(bases) | CsOp::Insertion(bases) => bases.len(),
+            CsOp::Substitution { .. } => 1,
+            CsOp::Deletion(_) | CsOp::Intron { .. } => 0,
+        }
+    }
+
+    /**
+     * Number of bases of the path covered by the operation
+     */
+    pub fn target_length(&self) -> usize {
+        match self {
+            CsOp::Match(length) | CsOp::Intron { length, .. } => *length,
+            CsOp::Identical(bases) | CsOp::Deletion(bases) => bases.len(),
+            CsOp::Substitution { .. } => 1,
+            CsOp::Insertion(_) => 0,
+        }
+    }
+}
+
+/**
+ * Parsed cs string, either in short form (`:10`) or long form (`=ACGTACGTAC`), or a mix of both
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cs {
+    pub ops: Vec<CsOp>,
+}
+
+impl Cs {
+    pub fn parse(cs: &str) -> Result<Self, GafError> {
+        let invalid = || GafError::InvalidCs(Location::new("cs", cs));
+        let is_bases =
+            |bases: &str| !bases.is_empty() && bases.bytes().all(|b| b.is_ascii_alphabetic());
+        let mut splits: Vec<_> = cs
+            .match_indices([':', '=', '*', '+', '-', '~'])
+            .map(|(i, _)| i)
+            .collect();
+        // the operations are sliced by bytes, bases and lengths are ASCII anyway
+        if !cs.is_ascii() || (splits.first() != Some(&0) && !cs.is_empty()) {
+            return Err(invalid());
+        }
+        splits.push(cs.len());
+        let ops = splits
+            .windows(2)
+            .map(|indexes| {
+                let op = &cs[indexes[0]..indexes[1]];
+                let value = &op[1..];
+                Ok(match &op[..1] {
+                    ":" => match value.parse() {
+                        Ok(length) if length > 0 => CsOp::Match(length),
+                        _ => return Err(invalid()),
+                    },
+                    "=" if is_bases(value) => CsOp::Identical(value.to_string()),
+                    "*" if is_bases(value) && value.len() == 2 => {
+                        let mut bases = value.chars();
+                        CsOp::Substitution {
+                            target: bases.next().ok_or_else(invalid)?,
+                            query: bases.next().ok_or_else(invalid)?,
+                        }
+                    }
+                    "+" if is_bases(value) => CsOp::Insertion(value.to_string()),
+                    "-" if is_bases(value) => CsOp::Deletion(value.to_string()),
+                    "~" if value.len() > 4
+                        && is_bases(&value[..2])
+                        && is_bases(&value[value.len() - 2..]) =>
+                    {
+                        CsOp::Intron {
+                            donor: value[..2].to_string(),
+                            length: value[2..value.len() - 2].parse().map_err(|_| invalid())?,
+                            acceptor: value[value.len() - 2..].to_string(),
+                        }
+                    }
+                    _ => return Err(invalid()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { ops })
+    }
+
+    /**
+     * Number of bases of the read covered by the alignment
+     */
+    pub fn query_length(&self) -> usize {
+        self.ops.iter().map(CsOp::query_length).sum()
+    }
+
+    /**
+     * Number of bases of the path covered by the alignment
+     */
+    pub fn target_length(&self) -> usize {
+        self.ops.iter().map(CsOp::target_length).sum()
+    }
+
+    /**
+     * Same alignment in short form, identical bases are replaced by their count
+     */
+    pub fn to_short(&self) -> Self {
+        let mut ops: Vec<CsOp> = vec![];
+        for op in &self.ops {
+            let op = match op {
+                CsOp::Identical(bases) => CsOp::Match(bases.len()),
+                op => op.clone(),
+            };
+            match (ops.last_mut(), &op) {
+                (Some(CsOp::Match(previous)), CsOp::Match(length)) => *previous += length,
+                _ => ops.push(op),
+            }
+        }
+        Self { ops }
+    }
+
+    /**
+     * Same alignment in long form, `target` is the sequence of the path covered by the alignment
+     */
+    pub fn to_long(&self, target: &str) -> Result<Self, GafError> {
+        if !target.is_ascii() || target.len() != self.target_length() {
+            return Err(GafError::InvalidCs(Location::new("cs", &self.to_string())));
+        }
+        let mut offset = 0;
+        let ops = self
+            .ops
+            .iter()
+            .map(|op| {
+                let bases = &target[offset..offset + op.target_length()];
+                offset += op.target_length();
+                match op {
+                    CsOp::Match(_) => CsOp::Identical(bases.to_ascii_uppercase()),
+                    op => op.clone(),
+                }
+            })
+            .collect();
+        Ok(Self { ops })
+    }
+}
+
+/**
+ * cs string as written in the GAF
+ */
+impl fmt::Display for Cs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for op in &self.ops {
+            match op {
+                CsOp::Match(length) => write!(f, ":{}", length)?,
+                CsOp::Identical(bases) => write!(f, "={}", bases)?,
+                CsOp::Substitution { target, query } => write!(f, "*{}{}", target, query)?,
+                CsOp::Insertion(bases) => write!(f, "+{}", bases)?,
+                CsOp::Deletion(bases) => write!(f, "-{}", bases)?,
+                CsOp::Intron {
+                    donor,
+                    length,
+                    acceptor,
+                } => write!(f, "~{}{}{}", donor, length, acceptor)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GafRecord {
+    /**
+     * Parsed `cs` field, if present
+     */
+    pub fn cs(&self) -> Result<Option<Cs>, GafError> {
+        self.tag_str("cs")?.map(Cs::parse).transpose()
+    }
+
+    /**
+     * `cs` field in long form, the identical bases are read from the graph
+     */
+    pub fn long_cs(&self, graph: &GraphIndex) -> Result<Option<Cs>, ConversionError> {
+        let Some(cs) = self.cs()? else {
+            return Ok(None);
+        };
+        let record = self.resolve_stable_steps(graph)?.segments_to_nodes(graph)?;
+        let mut sequence = String::new();
+        for step in &record.path {
+            let node_id = step.name.parse()?;
+            sequence += graph.node_to_sequence(node_id, step.is_reverse)?;
+        }
+        let target = sequence
+            .get(record.path_start as usize..record.path_end as usize)
+            .ok_or_else(|| GafError::InvalidCs(Location::new("cs", &cs.to_string())))?;
+        Ok(Some(cs.to_long(target)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gfa::{optfields::OptionalFields, parser::GFAParser};
+
+    #[test]
+    fn cs_forms() -> Result<(), Box<dyn std::error::Error>> {
+        let short = ":3*ag+tt:1-g~gt12ag:2";
+        let cs = Cs::parse(short)?;
+        assert_eq!(cs.to_string(), short);
+        assert_eq!(cs.query_length(), 9);
+        assert_eq!(cs.target_length(), 20);
+
+        let long = "=ACG*ag+tt=T-g~gt12ag=CC";
+        let long_cs = Cs::parse(long)?;
+        assert_eq!(long_cs.to_short(), cs);
+        assert_eq!(cs.to_long("acgaTggtttttttttagCC")?, long_cs);
+        assert!(cs.to_long("ACG").is_err());
+        assert!(cs.to_long("acgaTggttttttttéagC").is_err());
+
+        for bad in [
+            "3:3", ":x", ":0", "*a", "*agt", "+", "-1", "~gt12", "=AC#", "~aé12ag",
+        ] {
+            let e = Cs::parse(bad).unwrap_err();
+            assert_eq!(e.kind(), "invalid cs", "{}", bad);
+        }
+
+        let parser: GFAParser<usize, OptionalFields> = GFAParser::new();
+        let graph = GraphIndex::new(&parser.parse_file("data/stable.gfa")?)?;
+        let line = "read1\t7\t0\t7\t+\t>chr1:2-8\t10\t0\t6\t5\t7\t60\tcs:Z::2*ga:1+t:2";
+        let record = GafRecord::parse(line)?;
+        let long_cs = record.long_cs(&graph)?.expect("missing cs");
+        assert_eq!(long_cs.to_string(), "=GT*ga=G+t=TT");
+        Ok(())
+    }
+}
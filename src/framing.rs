@@ -1,8 +1,11 @@
-use bytes::Buf;
-use flate2::{read::MultiGzDecoder, Compression, GzBuilder};
-use prost::encoding::{decode_varint, encode_varint};
+use crate::bgzf;
+pub use crate::bgzf::VirtualOffset;
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+use prost::encoding::encode_varint;
+use std::fs::File;
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::{self, BufReader, ErrorKind};
+use std::marker::PhantomData;
 
 // Include the `vg` module, which is generated from vg.proto.
 #[allow(clippy::all)]
@@ -12,7 +15,10 @@ pub mod vg {
 
 const MAX_GROUP_SIZE: usize = 1000;
 
-pub(crate) trait SupportedFormat: prost::Message + Default + Clone {
+/**
+ * A vg protobuf message that can be stored in a framed stream, identified by its type tag
+ */
+pub trait SupportedFormat: prost::Message + Default + Clone + 'static {
     fn type_tag() -> String;
 }
 
@@ -28,96 +34,950 @@ impl SupportedFormat for vg::MultipathAlignment {
     }
 }
 
-pub(crate) fn parse<Message: SupportedFormat>(data: impl Read) -> Result<Vec<Message>, FramingError> {
-    // Decompress data
-    let mut decoder = MultiGzDecoder::new(data);
-    let mut data = vec![];
-    decoder.read_to_end(&mut data)?;
-    let mut cursor = Cursor::new(data);
+impl SupportedFormat for vg::Graph {
+    fn type_tag() -> String {
+        "VG".to_string()
+    }
+}
+
+impl SupportedFormat for vg::Snarl {
+    fn type_tag() -> String {
+        "SNARL".to_string()
+    }
+}
+
+impl SupportedFormat for vg::SnarlTraversal {
+    fn type_tag() -> String {
+        "SNARLTRAVERSAL".to_string()
+    }
+}
+
+impl SupportedFormat for vg::Pileup {
+    fn type_tag() -> String {
+        "PILEUP".to_string()
+    }
+}
+
+impl SupportedFormat for vg::Locus {
+    fn type_tag() -> String {
+        "LOCUS".to_string()
+    }
+}
+
+impl SupportedFormat for vg::Translation {
+    fn type_tag() -> String {
+        "TRANSLATION".to_string()
+    }
+}
+
+pub fn parse<Message: SupportedFormat>(data: impl Read) -> Result<Vec<Message>, FramingError> {
+    Reader::new(data).collect()
+}
+
+pub fn parse_from_file<Message: SupportedFormat>(
+    path: impl AsRef<std::path::Path>,
+) -> Result<Vec<Message>, FramingError> {
+    let f = open(path)?;
+    parse(f)
+}
+
+/**
+ * Open a file for reading, `-` standing for the standard input
+ */
+pub(crate) fn open(path: impl AsRef<std::path::Path>) -> io::Result<Box<dyn Read>> {
+    if path.as_ref() == std::path::Path::new("-") {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/**
+ * Create a file for writing, `-` standing for the standard output
+ */
+pub(crate) fn create(path: impl AsRef<std::path::Path>) -> io::Result<Box<dyn Write>> {
+    if path.as_ref() == std::path::Path::new("-") {
+        Ok(Box::new(io::BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
 
-    let mut alignments = vec![];
-    while cursor.remaining() != 0 {
-        // Read number of messages in the group
-        let number_messages = decode_varint(&mut cursor)? - 1;
+/**
+ * Streaming reader over a gzipped, varint-framed stream of vg messages.
+ * Messages are decoded one at a time, so only the current message is kept in memory.
+ * BGZF input (as written by vg and by `Writer`) also supports virtual offsets and seeking.
+ */
+pub struct Reader<R: Read, Message: SupportedFormat> {
+    input: Framed<R>,
+    // Messages left to read in the current group
+    remaining: u64,
+    buffer: Vec<u8>,
+    message: PhantomData<Message>,
+}
+
+impl<R: Read, Message: SupportedFormat> Reader<R, Message> {
+    pub fn new(data: R) -> Self {
+        Self::with_threads(data, 1)
+    }
 
-        // Read type tag
-        let type_tag_len = decode_varint(&mut cursor)?;
-        let mut type_tag = vec![0; type_tag_len as usize];
-        cursor.read_exact(&mut type_tag)?;
-        let type_tag = String::from_utf8(type_tag)?;
-        // Should always be equal to GAM/MGAM
-        if type_tag != Message::type_tag() {
-            return Err(FramingError::InvalidTypeTag(type_tag, Message::type_tag()));
+    /**
+     * Reader inflating BGZF blocks ahead with `threads` worker threads.
+     * Other compressions are always decoded in the calling thread.
+     */
+    pub fn with_threads(data: R, threads: usize) -> Self {
+        Self {
+            input: Framed::new(data, threads),
+            remaining: 0,
+            buffer: vec![],
+            message: PhantomData,
         }
+    }
 
-        // Read all messages in the group
-        for _ in 0..number_messages {
-            let message_len = decode_varint(&mut cursor)?;
-            let mut buffer = vec![0; message_len as _];
-            cursor.read_exact(&mut buffer)?;
-            let mut tmp = &buffer[..];
-            let alignment = Message::decode(&mut tmp)?;
-            alignments.push(alignment);
+    /**
+     * Virtual offset of the next byte to be read, only available for BGZF input.
+     * Offsets taken between two groups can be given back to `seek_virtual`.
+     */
+    pub fn virtual_position(&mut self) -> Result<VirtualOffset, FramingError> {
+        self.input.decoder.virtual_position()
+    }
+
+    /**
+     * Read the header of the next group, returning its number of messages
+     */
+    fn read_group_header(&mut self) -> Result<Option<u64>, FramingError> {
+        let Some(header) = self.input.read_group_header()? else {
+            return Ok(None);
+        };
+        // Should always be equal to the tag of the message type, untagged groups are trusted
+        match header.type_tag {
+            Some(type_tag) if type_tag != Message::type_tag() => {
+                Err(FramingError::InvalidTypeTag(type_tag, Message::type_tag()))
+            }
+            _ => Ok(Some(header.number_messages)),
         }
     }
-    Ok(alignments)
+
+    fn read_message(&mut self) -> Result<Option<Message>, FramingError> {
+        while self.remaining == 0 {
+            let Some(number_messages) = self.read_group_header()? else {
+                return Ok(None);
+            };
+            self.remaining = number_messages;
+        }
+
+        self.input.read_message(&mut self.buffer)?;
+        let message = Message::decode(&self.buffer[..])?;
+        self.remaining -= 1;
+        Ok(Some(message))
+    }
+
+    /**
+     * Read the rest of the current group, or the whole next group if the current one is over
+     */
+    pub(crate) fn read_group(&mut self) -> Result<Option<Vec<Message>>, FramingError> {
+        if self.remaining == 0 {
+            let Some(number_messages) = self.read_group_header()? else {
+                return Ok(None);
+            };
+            self.remaining = number_messages;
+        }
+        // The group grows with the messages read, the count in its header is not trusted
+        let mut group = vec![];
+        while self.remaining > 0 {
+            group.extend(self.read_message()?);
+        }
+        Ok(Some(group))
+    }
 }
 
-pub(crate) fn write<Message: SupportedFormat>(
-    alignments: &[Message],
-    mut out_file: impl Write,
-) -> Result<(), FramingError> {
-    let alignments = alignments.to_owned();
-    let mut buf = vec![];
+impl<R: Read + Seek, Message: SupportedFormat> Reader<R, Message> {
+    /**
+     * Move to the given virtual offset, which must be the start of a group
+     */
+    pub fn seek_virtual(&mut self, offset: VirtualOffset) -> Result<(), FramingError> {
+        self.input.decoder.seek_virtual(offset)?;
+        self.input.pending_message = None;
+        self.remaining = 0;
+        Ok(())
+    }
+}
 
-    for group in alignments.chunks(MAX_GROUP_SIZE) {
-        // This step may be optional
-        write_group(group, &mut buf)?;
+impl<R: Read, Message: SupportedFormat> Iterator for Reader<R, Message> {
+    type Item = Result<Message, FramingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/**
+ * A group of messages of any of the supported types
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Group {
+    Alignment(Vec<vg::Alignment>),
+    MultipathAlignment(Vec<vg::MultipathAlignment>),
+    Graph(Vec<vg::Graph>),
+    Snarl(Vec<vg::Snarl>),
+    SnarlTraversal(Vec<vg::SnarlTraversal>),
+    Pileup(Vec<vg::Pileup>),
+    Locus(Vec<vg::Locus>),
+    Translation(Vec<vg::Translation>),
+    // Type tag not known by this crate, the messages are left encoded
+    Unknown {
+        type_tag: String,
+        messages: Vec<Vec<u8>>,
+    },
+    // Legacy group without type tag, the messages are left encoded
+    Untagged(Vec<Vec<u8>>),
+}
+
+impl Group {
+    pub fn type_tag(&self) -> String {
+        match self {
+            Group::Alignment(_) => vg::Alignment::type_tag(),
+            Group::MultipathAlignment(_) => vg::MultipathAlignment::type_tag(),
+            Group::Graph(_) => vg::Graph::type_tag(),
+            Group::Snarl(_) => vg::Snarl::type_tag(),
+            Group::SnarlTraversal(_) => vg::SnarlTraversal::type_tag(),
+            Group::Pileup(_) => vg::Pileup::type_tag(),
+            Group::Locus(_) => vg::Locus::type_tag(),
+            Group::Translation(_) => vg::Translation::type_tag(),
+            Group::Unknown { type_tag, .. } => type_tag.clone(),
+            Group::Untagged(_) => String::new(),
+        }
     }
 
-    // Compress data
-    // FIXME For big files vg uses multi streams
-    // This is not currently supported by flate2, see this PR:
-    // https://github.com/rust-lang/flate2-rs/pull/325
-    let mut encoder = GzBuilder::new().write(&mut out_file, Compression::new(9));
-    encoder.write_all(&buf)?;
+    fn decode(group: RawGroup) -> Result<Self, FramingError> {
+        fn decode_all<Message: SupportedFormat>(
+            messages: &[Vec<u8>],
+        ) -> Result<Vec<Message>, FramingError> {
+            Ok(messages
+                .iter()
+                .map(|message| Message::decode(&message[..]))
+                .collect::<Result<_, _>>()?)
+        }
+
+        let RawGroup { type_tag, messages } = group;
+        let Some(type_tag) = type_tag else {
+            return Ok(Group::Untagged(messages));
+        };
+        Ok(if type_tag == vg::Alignment::type_tag() {
+            Group::Alignment(decode_all(&messages)?)
+        } else if type_tag == vg::MultipathAlignment::type_tag() {
+            Group::MultipathAlignment(decode_all(&messages)?)
+        } else if type_tag == vg::Graph::type_tag() {
+            Group::Graph(decode_all(&messages)?)
+        } else if type_tag == vg::Snarl::type_tag() {
+            Group::Snarl(decode_all(&messages)?)
+        } else if type_tag == vg::SnarlTraversal::type_tag() {
+            Group::SnarlTraversal(decode_all(&messages)?)
+        } else if type_tag == vg::Pileup::type_tag() {
+            Group::Pileup(decode_all(&messages)?)
+        } else if type_tag == vg::Locus::type_tag() {
+            Group::Locus(decode_all(&messages)?)
+        } else if type_tag == vg::Translation::type_tag() {
+            Group::Translation(decode_all(&messages)?)
+        } else {
+            Group::Unknown { type_tag, messages }
+        })
+    }
+}
+
+// Group whose messages are not decoded yet
+struct RawGroup {
+    type_tag: Option<String>,
+    messages: Vec<Vec<u8>>,
+}
+
+/**
+ * Streaming reader over a framed stream holding any kind of vg messages, one group at a time.
+ * The type of each group is given by its type tag rather than known in advance.
+ */
+pub struct AnyReader<R: Read> {
+    input: Framed<R>,
+}
+
+impl<R: Read> AnyReader<R> {
+    pub fn new(data: R) -> Self {
+        Self {
+            input: Framed::new(data, 1),
+        }
+    }
+
+    /**
+     * Read the type tag and the encoded messages of the next group
+     */
+    fn read_raw_group(&mut self) -> Result<Option<RawGroup>, FramingError> {
+        let Some(header) = self.input.read_group_header()? else {
+            return Ok(None);
+        };
+        let mut messages = vec![];
+        for _ in 0..header.number_messages {
+            let mut buffer = vec![];
+            self.input.read_message(&mut buffer)?;
+            messages.push(buffer);
+        }
+        Ok(Some(RawGroup {
+            type_tag: header.type_tag,
+            messages,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for AnyReader<R> {
+    type Item = Result<Group, FramingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_raw_group()
+            .and_then(|group| group.map(Group::decode).transpose())
+            .transpose()
+    }
+}
+
+/**
+ * Type tags found in a framed file, in order of first appearance.
+ * Legacy groups without a type tag are reported with an empty tag.
+ */
+pub fn probe(path: impl AsRef<std::path::Path>) -> Result<Vec<String>, FramingError> {
+    let mut reader = AnyReader::new(open(path)?);
+    let mut type_tags: Vec<String> = vec![];
+    while let Some(group) = reader.read_raw_group()? {
+        let type_tag = group.type_tag.unwrap_or_default();
+        if !type_tags.contains(&type_tag) {
+            type_tags.push(type_tag);
+        }
+    }
+    Ok(type_tags)
+}
+
+/**
+ * Read a varint from the stream, returning `None` if the stream ends before its first byte
+ */
+pub(crate) fn read_varint(reader: &mut impl BufRead) -> Result<Option<u64>, FramingError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let buf = reader.fill_buf()?;
+        let Some(&byte) = buf.first() else {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        };
+        reader.consume(1);
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "invalid varint").into())
+}
+
+/**
+ * Framed input: groups of length-prefixed messages, usually starting with a type tag
+ */
+struct Framed<R: Read> {
+    decoder: Decoder<R>,
+    // First message of an untagged group, read while looking for the type tag
+    pending_message: Option<Vec<u8>>,
+}
+
+struct GroupHeader {
+    number_messages: u64,
+    // Missing in legacy streams, written before vg introduced type tags
+    type_tag: Option<String>,
+}
+
+impl<R: Read> Framed<R> {
+    fn new(data: R, threads: usize) -> Self {
+        Self {
+            decoder: Decoder::new(data, threads),
+            pending_message: None,
+        }
+    }
+
+    /**
+     * Read the header of the next group, returning its number of messages and its type tag
+     */
+    fn read_group_header(&mut self) -> Result<Option<GroupHeader>, FramingError> {
+        loop {
+            // Read number of messages in the group, the type tag included
+            let Some(number_messages) = read_varint(&mut self.decoder)? else {
+                return Ok(None);
+            };
+            if number_messages == 0 {
+                continue;
+            }
+
+            // Read type tag, like vg if it is not a valid tag it is the first message of the group
+            let mut type_tag = vec![];
+            self.read_message(&mut type_tag)?;
+            if !is_valid_tag(&type_tag) {
+                self.pending_message = Some(type_tag);
+                return Ok(Some(GroupHeader {
+                    number_messages,
+                    type_tag: None,
+                }));
+            }
+            return Ok(Some(GroupHeader {
+                number_messages: number_messages - 1,
+                type_tag: Some(String::from_utf8(type_tag)?),
+            }));
+        }
+    }
+
+    /**
+     * Read the next encoded message of the group in `buffer`
+     */
+    fn read_message(&mut self, buffer: &mut Vec<u8>) -> Result<(), FramingError> {
+        if let Some(message) = self.pending_message.take() {
+            *buffer = message;
+            return Ok(());
+        }
+        let message_len = read_varint(&mut self.decoder)?.ok_or(ErrorKind::UnexpectedEof)?;
+        if message_len > MAX_MESSAGE_SIZE {
+            return Err(FramingError::MessageTooLong(message_len));
+        }
+        // Read without allocating the whole length first, a truncated stream fails early
+        buffer.clear();
+        (&mut self.decoder).take(message_len).read_to_end(buffer)?;
+        if buffer.len() as u64 != message_len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+// Longest type tag, as in vg
+const MAX_TAG_LENGTH: usize = 25;
+
+// Longest message, as in vg
+const MAX_MESSAGE_SIZE: u64 = 1_000_000_000;
+
+/**
+ * Check if the first message of a group looks like a type tag rather than a protobuf message
+ */
+fn is_valid_tag(tag: &[u8]) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LENGTH
+        && tag.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/**
+ * Decompressed input, the compression is detected on the first read
+ */
+pub(crate) enum Decoder<R: Read> {
+    Pending {
+        data: Option<R>,
+        // Worker threads inflating BGZF blocks
+        threads: usize,
+    },
+    Plain(BufReader<Rewind<R>>),
+    Bgzf(bgzf::Reader<BufReader<Rewind<R>>>),
+    Gzip(BufReader<MultiGzDecoder<BufReader<Rewind<R>>>>),
+}
+
+impl<R: Read> Decoder<R> {
+    pub(crate) fn new(data: R, threads: usize) -> Self {
+        Decoder::Pending {
+            data: Some(data),
+            threads,
+        }
+    }
+
+    pub(crate) fn detect(&mut self) -> io::Result<()> {
+        if let Decoder::Pending { data, threads } = self {
+            let threads = *threads;
+            let mut data = data
+                .take()
+                .ok_or_else(|| io::Error::other("input could not be read"))?;
+            // A single read may return less than a header, from a pipe for example
+            let mut header = Vec::with_capacity(bgzf::HEADER_SIZE);
+            (&mut data)
+                .take(bgzf::HEADER_SIZE as u64)
+                .read_to_end(&mut header)?;
+            let is_bgzf = bgzf::is_bgzf(&header);
+            let is_gzip = header.starts_with(&GZIP_MAGIC);
+            let data = BufReader::new(Rewind {
+                header,
+                position: 0,
+                data,
+            });
+            *self = if is_bgzf {
+                Decoder::Bgzf(bgzf::Reader::with_threads(data, threads))
+            } else if is_gzip {
+                Decoder::Gzip(BufReader::new(MultiGzDecoder::new(data)))
+            } else {
+                // Not compressed
+                Decoder::Plain(data)
+            };
+        }
+        Ok(())
+    }
+
+    /**
+     * Virtual offset of the next byte to be read, only available for BGZF input
+     */
+    pub(crate) fn virtual_position(&mut self) -> Result<VirtualOffset, FramingError> {
+        self.detect()?;
+        match self {
+            Decoder::Bgzf(decoder) => Ok(decoder.virtual_position()),
+            _ => Err(FramingError::NotBgzf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    pub(crate) fn seek_virtual(&mut self, offset: VirtualOffset) -> Result<(), FramingError> {
+        self.detect()?;
+        match self {
+            Decoder::Bgzf(decoder) => Ok(decoder.seek_virtual(offset)?),
+            _ => Err(FramingError::NotBgzf),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for Decoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.detect()?;
+        match self {
+            Decoder::Pending { .. } => unreachable!(),
+            Decoder::Plain(decoder) => decoder.fill_buf(),
+            Decoder::Bgzf(decoder) => decoder.fill_buf(),
+            Decoder::Gzip(decoder) => decoder.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Decoder::Pending { .. } => (),
+            Decoder::Plain(decoder) => decoder.consume(amt),
+            Decoder::Bgzf(decoder) => decoder.consume(amt),
+            Decoder::Gzip(decoder) => decoder.consume(amt),
+        }
+    }
+}
+
+/**
+ * Input whose first bytes were read to detect the compression, they are read again first
+ */
+pub(crate) struct Rewind<R> {
+    header: Vec<u8>,
+    position: usize, // next byte of the header to read
+    data: R,
+}
+
+impl<R: Read> Read for Rewind<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.header.len() {
+            let len = (self.header.len() - self.position).min(buf.len());
+            buf[..len].copy_from_slice(&self.header[self.position..self.position + len]);
+            self.position += len;
+            return Ok(len);
+        }
+        self.data.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for Rewind<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        // The header bytes not read yet are ahead of the position of the input
+        let pos = match pos {
+            io::SeekFrom::Current(offset) => {
+                io::SeekFrom::Current(offset - (self.header.len() - self.position) as i64)
+            }
+            pos => pos,
+        };
+        self.position = self.header.len();
+        self.data.seek(pos)
+    }
+}
+
+pub fn write<Message: SupportedFormat>(
+    alignments: &[Message],
+    out_file: impl Write,
+) -> Result<(), FramingError> {
+    let mut writer = Writer::new(out_file);
+    for alignment in alignments {
+        writer.write_record(alignment)?;
+    }
+    writer.finish()?;
     Ok(())
 }
 
-fn write_group<Message: SupportedFormat>(
+pub fn write_to_file<Message: SupportedFormat>(
     alignments: &[Message],
-    mut file: impl Write,
+    path: impl AsRef<std::path::Path>,
 ) -> Result<(), FramingError> {
-    let mut buf = vec![];
+    let mut f = create(path)?;
+    write(alignments, &mut f)?;
+    Ok(f.flush()?)
+}
+
+/**
+ * Compression of a framed stream
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    // Raw framed protobuf, as `vg view -a` style pipes
+    None,
+    // A single gzip stream with the given level (0-9)
+    Gzip(u32),
+    // BGZF blocks with the given level (0-9), as vg writes
+    Bgzf(u32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Bgzf(9)
+    }
+}
+
+/**
+ * Incremental writer of a varint-framed stream of vg messages, BGZF-compressed by default.
+ * Messages are buffered until a group of `MAX_GROUP_SIZE` is complete, then written out.
+ * Like vg, every group ends a BGZF block. Call `finish` to write the last group and close the stream.
+ */
+pub struct Writer<W: Write, Message: SupportedFormat> {
+    encoder: Option<Encoder<W>>,
+    // Encoded messages of the current group, each prefixed by its length
+    group: Vec<u8>,
+    // Messages of the current group when it is encoded by the worker threads
+    messages: Option<Vec<Message>>,
+    group_size: usize,
+}
+
+impl<W: Write, Message: SupportedFormat> Writer<W, Message> {
+    pub fn new(out_file: W) -> Self {
+        Self::with_compression(out_file, Compression::default())
+    }
+
+    pub fn with_compression(out_file: W, compression: Compression) -> Self {
+        Self::with_threads(out_file, compression, 1)
+    }
+
+    /**
+     * Writer encoding and deflating groups with `threads` worker threads, records keep their order.
+     * Other compressions than BGZF are always encoded in the calling thread.
+     */
+    pub fn with_threads(out_file: W, compression: Compression, threads: usize) -> Self {
+        let encoder = Encoder::new(out_file, compression, threads);
+        let parallel = matches!(&encoder, Encoder::Bgzf(encoder) if encoder.threads() > 1);
+        Self {
+            encoder: Some(encoder),
+            group: vec![],
+            messages: parallel.then(Vec::new),
+            group_size: 0,
+        }
+    }
+
+    pub fn write_record(&mut self, message: &Message) -> Result<(), FramingError> {
+        if let Some(messages) = &mut self.messages {
+            messages.push(message.clone());
+        } else {
+            // Write message length
+            encode_varint(message.encoded_len() as _, &mut self.group);
+            // Write message
+            message.encode(&mut self.group)?;
+        }
+        self.group_size += 1;
+        if self.group_size == MAX_GROUP_SIZE {
+            self.flush_group()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Write the pending group and close the compressed stream, returning the underlying writer
+     */
+    pub fn finish(mut self) -> Result<W, FramingError> {
+        self.flush_group()?;
+        let encoder = self.encoder.take().expect("writer already finished");
+        Ok(encoder.finish()?)
+    }
+
+    fn flush_group(&mut self) -> Result<(), FramingError> {
+        if self.group_size == 0 {
+            return Ok(());
+        }
+        let encoder = self.encoder.as_mut().expect("writer already finished");
+        let group_size = std::mem::take(&mut self.group_size);
+        if let Some(messages) = &mut self.messages {
+            let messages = std::mem::take(messages);
+            return Ok(encoder.write_group(move || {
+                let mut buf = vec![];
+                encode_group_header::<Message>(messages.len(), &mut buf);
+                for message in &messages {
+                    message.encode_length_delimited(&mut buf)?;
+                }
+                Ok(buf)
+            })?);
+        }
+
+        let mut buf = vec![];
+        encode_group_header::<Message>(group_size, &mut buf);
+        encoder.write_all(&buf)?;
+        // Write all messages
+        encoder.write_all(&self.group)?;
+        encoder.end_group()?;
+        self.group.clear();
+        Ok(())
+    }
+}
+
+/**
+ * Write the number of messages in a group and its type tag
+ */
+fn encode_group_header<Message: SupportedFormat>(group_size: usize, buf: &mut Vec<u8>) {
     // Write number of messages in the group
-    encode_varint(alignments.len() as u64 + 1, &mut buf);
+    encode_varint(group_size as u64 + 1, buf);
     // Write type tag
     let type_tag = Message::type_tag();
-    encode_varint(type_tag.len() as _, &mut buf);
-    file.write_all(&buf)?;
-    file.write_all(type_tag.as_bytes())?;
+    encode_varint(type_tag.len() as _, buf);
+    buf.extend_from_slice(type_tag.as_bytes());
+}
 
-    // Write all messages
-    for alignment in alignments {
-        let mut buf = vec![];
-        Message::encode(alignment, &mut buf)?;
-        let mut buf_len = vec![];
-        encode_varint(buf.len() as _, &mut buf_len);
-        // Write message length
-        file.write_all(&buf_len)?;
-        // Write message
-        file.write_all(&buf)?;
+impl<W: Write, Message: SupportedFormat> Drop for Writer<W, Message> {
+    fn drop(&mut self) {
+        // Best effort, like `BufWriter`: errors can only be observed through `finish`
+        if self.encoder.is_some() {
+            let _ = self.flush_group();
+        }
+    }
+}
+
+/**
+ * Compressed output
+ */
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Bgzf(bgzf::Writer<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(out_file: W, compression: Compression, threads: usize) -> Self {
+        match compression {
+            Compression::None => Encoder::Plain(out_file),
+            Compression::Gzip(level) => {
+                Encoder::Gzip(GzEncoder::new(out_file, flate2::Compression::new(level)))
+            }
+            Compression::Bgzf(level) => Encoder::Bgzf(bgzf::Writer::with_threads(
+                out_file,
+                flate2::Compression::new(level),
+                threads,
+            )),
+        }
+    }
+
+    /**
+     * Write a whole group produced by `group`, in the background for threaded BGZF
+     */
+    fn write_group(
+        &mut self,
+        group: impl FnOnce() -> Result<Vec<u8>, prost::EncodeError> + Send + 'static,
+    ) -> io::Result<()> {
+        let group = move || group().map_err(io::Error::other);
+        match self {
+            Encoder::Bgzf(encoder) => encoder.write_blocks(group),
+            _ => self.write_all(&group()?),
+        }
+    }
+
+    fn end_group(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Bgzf(encoder) => encoder.flush_block(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(encoder) => Ok(encoder),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Bgzf(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Bgzf(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Bgzf(encoder) => encoder.flush(),
+        }
     }
-    Ok(())
 }
 
 #[derive(thiserror::Error, Debug)]
 #[error(transparent)]
 pub enum FramingError {
-    Io(#[from] std::io::Error),
+    Io(#[from] io::Error),
     Utf8(#[from] std::string::FromUtf8Error),
     ProstDecode(#[from] prost::DecodeError),
     ProstEncode(#[from] prost::EncodeError),
     #[error("Type tag is {0}, expected \"{1}\"")]
     InvalidTypeTag(String, String),
+    #[error("Input is not BGZF-compressed, virtual offsets are not available")]
+    NotBgzf,
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    #[error("Message of {0} bytes is longer than the maximum size")]
+    MessageTooLong(u64),
+}
+
+impl From<ErrorKind> for FramingError {
+    fn from(kind: ErrorKind) -> Self {
+        Self::Io(kind.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn graph_write() -> Result<(), Box<dyn std::error::Error>> {
+        let graph = vg::Graph {
+            node: vec![vg::Node {
+                sequence: "ACGT".into(),
+                id: 1,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut data = vec![];
+        write(std::slice::from_ref(&graph), &mut data)?;
+
+        assert_eq!(parse::<vg::Graph>(&data[..])?, vec![graph]);
+        assert!(matches!(
+            parse::<vg::Snarl>(&data[..]),
+            Err(FramingError::InvalidTypeTag(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn compression() -> Result<(), Box<dyn std::error::Error>> {
+        let alignments = parse_from_file::<vg::Alignment>("data/tiny.gam")?;
+        for compression in [
+            Compression::None,
+            Compression::Gzip(6),
+            Compression::Bgzf(1),
+        ] {
+            let mut writer = Writer::with_compression(vec![], compression);
+            for alignment in &alignments {
+                writer.write_record(alignment)?;
+            }
+            let data = writer.finish()?;
+            assert_eq!(
+                data.starts_with(&GZIP_MAGIC),
+                compression != Compression::None
+            );
+            assert_eq!(parse::<vg::Alignment>(&data[..])?, alignments);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn short_reads() -> Result<(), Box<dyn std::error::Error>> {
+        // Input returning one byte per read, like a slow pipe
+        struct ByteReader<R>(R);
+        impl<R: Read> Read for ByteReader<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(1);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        let alignments = parse_from_file::<vg::Alignment>("data/tiny.gam")?;
+        let data = std::fs::read("data/tiny.gam")?;
+        assert!(bgzf::is_bgzf(&data));
+        let mut reader = Reader::<_, vg::Alignment>::new(ByteReader(&data[..]));
+        reader.virtual_position()?;
+        assert_eq!(reader.collect::<Result<Vec<_>, _>>()?, alignments);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_lengths() -> Result<(), Box<dyn std::error::Error>> {
+        // A group announcing many messages, the first one longer than vg allows
+        let mut data = vec![];
+        encode_varint(u64::MAX >> 1, &mut data);
+        encode_varint(1 << 40, &mut data);
+        let mut reader = Reader::<_, vg::Alignment>::new(&data[..]);
+        assert!(matches!(
+            reader.read_group(),
+            Err(FramingError::MessageTooLong(_))
+        ));
+
+        // Within the limit, but past the end of the stream
+        let mut data = vec![];
+        encode_varint(2, &mut data);
+        encode_varint(MAX_MESSAGE_SIZE, &mut data);
+        let mut reader = Reader::<_, vg::Alignment>::new(&data[..]);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(FramingError::Io(e))) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_untagged() -> Result<(), Box<dyn std::error::Error>> {
+        let alignments = parse_from_file::<vg::Alignment>("data/tiny.gam")?;
+        // Groups written before type tags: number of messages, then the messages
+        let mut buf = vec![];
+        encode_varint(alignments.len() as u64, &mut buf);
+        for alignment in &alignments {
+            encode_varint(alignment.encoded_len() as u64, &mut buf);
+            alignment.encode(&mut buf)?;
+        }
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&buf)?;
+        let data = encoder.finish()?;
+
+        assert_eq!(parse::<vg::Alignment>(&data[..])?, alignments);
+        let group = AnyReader::new(&data[..]).next().unwrap()?;
+        assert!(matches!(group, Group::Untagged(messages) if messages.len() == alignments.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn any_reader() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(probe("data/example.gamp")?, vec!["MGAM"]);
+
+        let gam = parse_from_file::<vg::Alignment>("data/example.gam")?;
+        let mut alignments = vec![];
+        for group in AnyReader::new(File::open("data/example.gam")?) {
+            let Group::Alignment(group) = group? else {
+                panic!("expected a GAM group");
+            };
+            alignments.extend(group);
+        }
+        assert_eq!(alignments, gam);
+        Ok(())
+    }
 }
@@ -1,51 +1,381 @@
-use crate::{graph::GFAExt, vg, ConversionError};
-use gfa::gfa::GFA;
+pub use crate::cs::{Cs, CsOp};
+pub use crate::gafindex::{sort_records, GafIndex, IndexedReader, Query};
+pub use crate::gaftag::{TagArray, TagValue};
+use crate::{
+    framing::{self, Compression, Decoder, Encoder, FramingError, VirtualOffset},
+    gaftag,
+    graph::{node_slice, GFAExt, GraphIndex},
+    vg, ConversionError,
+};
+use indexmap::IndexMap;
 use prost_types::value::Kind;
 use pyo3::FromPyObject;
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Read, Write},
+    collections::BTreeMap,
+    io::{BufRead, BufWriter, Read, Seek, Write},
 };
 
 #[derive(thiserror::Error, Debug)]
 #[error(transparent)]
 pub enum GafError {
     Io(#[from] std::io::Error),
-    ParseInt(#[from] std::num::ParseIntError),
+    Framing(#[from] FramingError),
     #[error("Missing start in interval step")]
     MissingStart,
     #[error("Missing end in interval step")]
     MissingEnd,
-    #[error("Not enough tokens in line")]
-    MissingToken,
+    #[error("Not enough tokens in {0}")]
+    MissingToken(Location),
+    #[error("Invalid number in {0}")]
+    InvalidNumber(Location),
+    #[error("Invalid strand in {0}")]
+    InvalidStrand(Location),
+    #[error("Invalid interval step in {0}")]
+    InvalidInterval(Location),
+    #[error("Invalid optional field in {0}")]
+    InvalidTag(Location),
+    #[error("Duplicate optional field in {0}")]
+    DuplicateTag(Location),
+    #[error("Invalid CIGAR in {0}")]
+    InvalidCigar(Location),
+    #[error("CIGAR does not match the record coordinates in {0}")]
+    CigarMismatch(Location),
+    #[error("Invalid cs string in {0}")]
+    InvalidCs(Location),
+}
+
+impl GafError {
+    /**
+     * Where the error was found, for errors raised while parsing a line
+     */
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            GafError::MissingToken(location)
+            | GafError::InvalidNumber(location)
+            | GafError::InvalidStrand(location)
+            | GafError::InvalidInterval(location)
+            | GafError::InvalidTag(location)
+            | GafError::DuplicateTag(location)
+            | GafError::InvalidCigar(location)
+            | GafError::CigarMismatch(location)
+            | GafError::InvalidCs(location) => Some(location),
+            _ => None,
+        }
+    }
+
+    /**
+     * Short name of the kind of error
+     */
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GafError::Io(_) => "io",
+            GafError::Framing(_) => "framing",
+            GafError::MissingStart => "missing start",
+            GafError::MissingEnd => "missing end",
+            GafError::MissingToken(_) => "missing token",
+            GafError::InvalidNumber(_) => "invalid number",
+            GafError::InvalidStrand(_) => "invalid strand",
+            GafError::InvalidInterval(_) => "invalid interval",
+            GafError::InvalidTag(_) => "invalid tag",
+            GafError::DuplicateTag(_) => "duplicate tag",
+            GafError::InvalidCigar(_) => "invalid cigar",
+            GafError::CigarMismatch(_) => "cigar mismatch",
+            GafError::InvalidCs(_) => "invalid cs",
+        }
+    }
+
+    /**
+     * Set the line number (1-based) of a parse error
+     */
+    fn at_line(mut self, line_number: Option<usize>) -> Self {
+        match &mut self {
+            GafError::MissingToken(location)
+            | GafError::InvalidNumber(location)
+            | GafError::InvalidStrand(location)
+            | GafError::InvalidInterval(location)
+            | GafError::InvalidTag(location)
+            | GafError::DuplicateTag(location)
+            | GafError::InvalidCigar(location)
+            | GafError::CigarMismatch(location)
+            | GafError::InvalidCs(location) => location.line = line_number,
+            _ => (),
+        }
+        self
+    }
+}
+
+/**
+ * Position of a parse error: the line, the column and the text that could not be parsed
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub line: Option<usize>, // 1-based, only known when reading a file
+    pub column: &'static str,
+    pub text: String,
 }
 
+impl Location {
+    pub(crate) fn new(column: &'static str, text: &str) -> Self {
+        Self {
+            line: None,
+            column,
+            text: text.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}, ", line)?;
+        }
+        write!(f, "column {}: {:?}", self.column, self.text)
+    }
+}
+
+/**
+ * Parse a GAF, plain text or compressed with gzip or bgzip
+ */
 pub fn parse(data: impl Read) -> Result<Vec<GafRecord>, GafError> {
-    let mut string = String::new();
-    let mut reader = std::io::BufReader::new(data);
-    reader.read_to_string(&mut string)?;
-    string.lines().map(|line| GafRecord::parse(line)).collect()
+    Reader::new(data).collect()
+}
+
+/**
+ * Parse a GAF, returning the report of the invalid lines along with the records
+ */
+pub fn parse_with_options(
+    data: impl Read,
+    options: ParseOptions,
+) -> Result<(Vec<GafRecord>, ParseReport), GafError> {
+    let mut reader = Reader::with_options(data, options);
+    let records = reader.by_ref().collect::<Result<_, _>>()?;
+    Ok((records, reader.report))
 }
 
 pub fn parse_from_file(path: impl AsRef<std::path::Path>) -> Result<Vec<GafRecord>, GafError> {
-    let f = File::open(path)?;
+    let f = framing::open(path)?;
     parse(f)
 }
 
-pub fn write(records: &Vec<GafRecord>, mut out_file: impl Write) -> Result<(), GafError> {
+pub fn write(records: &[GafRecord], out_file: impl Write) -> Result<(), GafError> {
+    let mut writer = Writer::new(out_file);
     for record in records {
-        record.write(&mut out_file)?;
+        writer.write_record(record)?;
     }
+    writer.finish()?;
     Ok(())
 }
 
+/**
+ * Write a GAF file, bgzip-compressed if the path ends with `.gz`
+ */
 pub fn write_to_file(
-    records: &Vec<GafRecord>,
+    records: &[GafRecord],
     path: impl AsRef<std::path::Path>,
 ) -> Result<(), GafError> {
-    let f = File::create(path)?;
-    write(records, f)
+    let compression = if path.as_ref().extension().is_some_and(|ext| ext == "gz") {
+        Compression::default()
+    } else {
+        Compression::None
+    };
+    let mut f = framing::create(path)?;
+    let mut writer = Writer::with_compression(&mut f, compression);
+    for record in records {
+        writer.write_record(record)?;
+    }
+    writer.finish()?;
+    Ok(f.flush()?)
+}
+
+/**
+ * How to handle invalid lines
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    // Fail on the first invalid line
+    #[default]
+    Strict,
+    // Skip invalid lines, reporting them
+    SkipInvalid,
+    // Like `SkipInvalid`, but first try to repair lines with trailing whitespace and to drop blank lines
+    Repair,
+}
+
+/**
+ * Options of the GAF reader
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    pub strictness: Strictness,
+}
+
+// Errors kept by a report, the following ones are only counted
+pub const MAX_REPORTED_ERRORS: usize = 100;
+
+/**
+ * Invalid lines met by a lenient reader
+ */
+#[derive(Debug, Default)]
+pub struct ParseReport {
+    // Errors of the first `MAX_REPORTED_ERRORS` skipped lines, in file order
+    pub errors: Vec<GafError>,
+    // Number of skipped lines
+    pub skipped: usize,
+    // Number of skipped lines per kind of error
+    pub counts: BTreeMap<&'static str, usize>,
+    // Number of lines repaired or dropped because they were blank
+    pub repaired: usize,
+}
+
+impl ParseReport {
+    fn add(&mut self, e: GafError) {
+        *self.counts.entry(e.kind()).or_default() += 1;
+        self.skipped += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(e);
+        }
+    }
+
+    /**
+     * Number of skipped lines, including the ones whose error is not kept
+     */
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+/**
+ * Streaming GAF reader, parsing one line at a time so that only the current record is kept in memory.
+ * Gzip and BGZF input is detected and decompressed on the fly.
+ */
+pub struct Reader<R: Read> {
+    data: Decoder<R>,
+    line: String,
+    // Number of the last line read, unknown after seeking in the middle of the file
+    line_number: Option<usize>,
+    options: ParseOptions,
+    report: ParseReport,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(data: R) -> Self {
+        Self::with_options(data, ParseOptions::default())
+    }
+
+    pub fn with_options(data: R, options: ParseOptions) -> Self {
+        Self {
+            data: Decoder::new(data, 1),
+            line: String::new(),
+            line_number: Some(0),
+            options,
+            report: ParseReport::default(),
+        }
+    }
+
+    /**
+     * Lines skipped or repaired so far, always empty in strict mode
+     */
+    pub fn report(&self) -> &ParseReport {
+        &self.report
+    }
+
+    /**
+     * Virtual offset of the next line, only available for BGZF input
+     */
+    pub fn virtual_position(&mut self) -> Result<VirtualOffset, GafError> {
+        Ok(self.data.virtual_position()?)
+    }
+
+    pub(crate) fn read_record(&mut self) -> Result<Option<GafRecord>, GafError> {
+        loop {
+            self.line.clear();
+            if self.data.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number = self.line_number.map(|line_number| line_number + 1);
+            let line = self.line.strip_suffix('\n').unwrap_or(&self.line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let e = match GafRecord::parse(line) {
+                Ok(record) => return Ok(Some(record)),
+                Err(e) => e.at_line(self.line_number),
+            };
+
+            if self.options.strictness == Strictness::Repair {
+                // Blank lines and trailing whitespace can safely be dropped
+                let repaired = line.trim_end();
+                if repaired.is_empty() {
+                    self.report.repaired += 1;
+                    continue;
+                }
+                if repaired.len() < line.len() {
+                    if let Ok(record) = GafRecord::parse(repaired) {
+                        self.report.repaired += 1;
+                        return Ok(Some(record));
+                    }
+                }
+            }
+            // Only errors in the content of a line can be skipped
+            if self.options.strictness == Strictness::Strict || e.location().is_none() {
+                return Err(e);
+            }
+            self.report.add(e);
+        }
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /**
+     * Move to the given virtual offset, which must be the start of a line.
+     * Parse errors after the seek have no line number, unless back at the start of the file.
+     */
+    pub fn seek_virtual(&mut self, offset: VirtualOffset) -> Result<(), GafError> {
+        self.data.seek_virtual(offset)?;
+        self.line_number = (offset == VirtualOffset::default()).then_some(0);
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<GafRecord, GafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/**
+ * Incremental GAF writer, buffering its output, in plain text by default.
+ * Call `finish` to flush the last records, close the compressed stream and get back the underlying writer.
+ */
+pub struct Writer<W: Write> {
+    out: BufWriter<Encoder<W>>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out_file: W) -> Self {
+        Self::with_compression(out_file, Compression::None)
+    }
+
+    /**
+     * Writer compressing its output, `Compression::Bgzf` gives the same format as `bgzip`
+     */
+    pub fn with_compression(out_file: W, compression: Compression) -> Self {
+        Self {
+            out: BufWriter::new(Encoder::new(out_file, compression, 1)),
+        }
+    }
+
+    pub fn write_record(&mut self, record: &GafRecord) -> Result<(), GafError> {
+        record.write(&mut self.out)
+    }
+
+    pub fn finish(self) -> Result<W, GafError> {
+        let encoder = self
+            .out
+            .into_inner()
+            .map_err(|e| GafError::Io(e.into_error()))?;
+        Ok(encoder.finish()?)
+    }
 }
 
 /**
@@ -65,7 +395,7 @@ pub struct GafStep {
     pub is_stable: bool,    // True if it's a stable path name (as opposed to segment/node name)
     pub is_interval: bool, // True if it's an interval of a stable path (false if it's the whole path)
     pub start: Option<i64>, // 0-based start (inclusive). only defined if is_stable and is_interval are true
-    pub end: Option<i64>, // 0-based end (inclusive). only defined if is_stable and is_interval are true
+    pub end: Option<i64>, // 0-based end (exclusive). only defined if is_stable and is_interval are true
 }
 
 impl GafStep {
@@ -112,16 +442,31 @@ pub struct GafRecord {
     pub strand: char,       // strand relative to the path + or -
     pub path: Vec<GafStep>, // the path
 
-    // Map a tag name to its type and value
+    // Map a tag name to its type and value, see `tag` for typed values
     // ex: "de:f:0.2183" in the GAF would appear as opt_fields["de"] = ("f", "0.2183")
-    pub opt_fields: HashMap<String, (String, String)>,
+    // Tags are kept in the order they were read or inserted, which is the order they are written in
+    pub opt_fields: IndexMap<String, (String, String)>,
 }
 
-fn number_or_missing(token: &str) -> Result<i64, GafError> {
+/**
+ * Split a step as `name:start-end`, at the last colon so that names may contain colons.
+ * Returns `None` without colon, and no interval if it is not made of two numbers.
+ */
+fn split_interval(step: &str) -> Option<(&str, Option<(i64, i64)>)> {
+    let (name, interval) = step.rsplit_once(':')?;
+    let interval = interval
+        .split_once('-')
+        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+    Some((name, interval))
+}
+
+fn number_or_missing(token: &str, column: &'static str) -> Result<i64, GafError> {
     Ok(if token == "*" {
         MISSING_INT
     } else {
-        token.parse::<i64>()?
+        token
+            .parse::<i64>()
+            .map_err(|_| GafError::InvalidNumber(Location::new(column, token)))?
     })
 }
 
@@ -131,21 +476,26 @@ impl GafRecord {
      */
     pub fn parse(line: &str) -> Result<Self, GafError> {
         let mut split = line.split('\t');
+        let mut next_token = |column| {
+            split
+                .next()
+                .ok_or_else(|| GafError::MissingToken(Location::new(column, line)))
+        };
+
+        let query_name = next_token("query name")?.to_string();
+        let query_length = number_or_missing(next_token("query length")?, "query length")?;
+        let query_start = number_or_missing(next_token("query start")?, "query start")?;
+        let query_end = number_or_missing(next_token("query end")?, "query end")?;
+        let token = next_token("strand")?;
+        let strand = match token {
+            "+" => '+',
+            "-" => '-',
+            _ => return Err(GafError::InvalidStrand(Location::new("strand", token))),
+        };
 
-        let mut token = split.next().ok_or(GafError::MissingToken)?;
-        let query_name = token.to_string();
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let query_length = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let query_start = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let query_end = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let strand = token.chars().next().unwrap();
-
-        token = split.next().ok_or(GafError::MissingToken)?;
+        let token = next_token("path")?;
         let mut path = Vec::new();
-        if token.to_string().starts_with(['<', '>']) {
+        if token.starts_with(['<', '>']) {
             // orientIntv
             let mut splits: Vec<_> = token.match_indices(['<', '>']).map(|(i, _)| i).collect();
             splits.push(token.len());
@@ -154,17 +504,15 @@ impl GafRecord {
                 .windows(2)
                 .map(|indexes| &token[indexes[0]..indexes[1]])
             {
-                let is_reverse = &step_token[0..1] == "<";
-                let s = match step_token.find(':') {
-                    Some(colon) => {
-                        let Some(dash) = step_token[colon..].find('-') else {
-                            panic!("Error parsing GAF range of {}", step_token)
-                        };
-                        let start = step_token[colon + 1..colon + dash].parse::<i64>()?;
-                        let end = step_token[colon + 1 + dash..].parse::<i64>()?;
+                let is_reverse = step_token.starts_with('<');
+                let s = match split_interval(&step_token[1..]) {
+                    Some((name, interval)) => {
                         // stableIntv
+                        let (start, end) = interval.ok_or_else(|| {
+                            GafError::InvalidInterval(Location::new("path", step_token))
+                        })?;
                         GafStep {
-                            name: (step_token[1..colon - 1]).to_string(),
+                            name: name.to_string(),
                             is_reverse,
                             is_stable: true,
                             is_interval: true,
@@ -187,45 +535,57 @@ impl GafRecord {
                 path.push(s);
             }
         } else {
-            // stableId
-            path.push(GafStep {
-                name: token.to_string(),
-                is_reverse: false,
-                is_stable: true,
-                is_interval: false,
-                start: None,
-                end: None,
-            });
+            match split_interval(token) {
+                // stableIntv without orientation, forward
+                Some((name, Some((start, end)))) => path.push(GafStep {
+                    name: name.to_string(),
+                    is_reverse: false,
+                    is_stable: true,
+                    is_interval: true,
+                    start: Some(start),
+                    end: Some(end),
+                }),
+                // stableId, whose name may contain colons
+                _ => path.push(GafStep {
+                    name: token.to_string(),
+                    is_reverse: false,
+                    is_stable: true,
+                    is_interval: false,
+                    start: None,
+                    end: None,
+                }),
+            }
         }
 
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let path_length = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let path_start = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let path_end = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let matches = number_or_missing(token)?;
-        token = split.next().ok_or(GafError::MissingToken)?;
-        let block_length = number_or_missing(token)?;
+        let path_length = number_or_missing(next_token("path length")?, "path length")?;
+        let path_start = number_or_missing(next_token("path start")?, "path start")?;
+        let path_end = number_or_missing(next_token("path end")?, "path end")?;
+        let matches = number_or_missing(next_token("matches")?, "matches")?;
+        let block_length = number_or_missing(next_token("block length")?, "block length")?;
 
-        token = split.next().ok_or(GafError::MissingToken)?;
+        let token = next_token("mapping quality")?;
         let mapq = if token == MISSING_STRING {
             MISSING_INT as _
         } else {
-            token.parse::<i32>()?
+            token
+                .parse::<i32>()
+                .map_err(|_| GafError::InvalidNumber(Location::new("mapping quality", token)))?
         };
 
-        let mut opt_fields = HashMap::new();
-        for opt_token in split.collect::<Vec<&str>>() {
-            let key: &str = &opt_token[..2];
-            let typ: &str = &opt_token[3..4];
-            let value: &str = &opt_token[5..];
+        let mut opt_fields = IndexMap::new();
+        for opt_token in split {
+            // TAG:TYPE:VALUE, with a two-character tag and a one-character type
+            let (key, typ, value) = match opt_token.splitn(3, ':').collect::<Vec<_>>()[..] {
+                [key, typ, value] if key.len() == 2 && gaftag::is_valid_type(typ) => {
+                    (key, typ, value)
+                }
+                _ => return Err(GafError::InvalidTag(Location::new("tag", opt_token))),
+            };
             if opt_fields
                 .insert(key.to_string(), (typ.to_string(), value.to_string()))
                 .is_some()
             {
-                panic!("Duplicate optional field found: {}", key)
+                return Err(GafError::DuplicateTag(Location::new("tag", opt_token)));
             }
         }
 
@@ -294,91 +654,336 @@ impl GafRecord {
         Ok(())
     }
 
-    pub fn iter_cigar(&self) -> Vec<Cigar> {
-        if self.opt_fields.contains_key("cs") {
-            self.iter_cs()
-                .into_iter()
-                .map(|cs| {
-                    let first_char = cs.chars().next().unwrap();
-                    match first_char {
-                        ':' => Cigar {
-                            cat: first_char,
-                            length: cs[1..].parse::<usize>().unwrap(),
-                            query: "".into(),
-                            target: "".into(),
-                        },
-                        '+' => {
-                            let query = &cs[1..];
-                            Cigar {
-                                cat: first_char.into(),
-                                length: query.len(),
-                                query: query.into(),
-                                target: "".into(),
-                            }
+    /**
+     * Copy of the record where the steps on stable sequences are replaced by the segments
+     * of the rGFA graph covering them, found with their SN and SO tags.
+     * The path coordinates are shifted to the resulting path of segments.
+     */
+    pub fn resolve_stable_steps(&self, graph: &GraphIndex) -> Result<GafRecord, ConversionError> {
+        let mut record = self.clone();
+        if !self.path.iter().any(|step| step.is_stable) {
+            return Ok(record);
+        }
+        record.path.clear();
+        let mut path_length = 0;
+        // Start of the path on its first segment
+        let mut path_offset = 0;
+        for (i, step) in self.path.iter().enumerate() {
+            if !step.is_stable {
+                path_length += graph.segment_to_length(&step.name)? as i64;
+                record.path.push(step.clone());
+                continue;
+            }
+
+            let unresolved = || {
+                let mut step_name = vec![];
+                // Writing to a Vec cannot fail
+                step.write(&mut step_name).ok();
+                ConversionError::UnresolvedStableStep(String::from_utf8_lossy(&step_name).into())
+            };
+            let segments = graph.stable_segments(&step.name);
+            let (start, end) = match (step.start, step.end) {
+                (Some(start), Some(end)) if step.is_interval => (start, end),
+                _ if !step.is_interval => segments
+                    .first()
+                    .zip(segments.last())
+                    .map(|(first, last)| (first.offset, last.offset + last.length))
+                    .ok_or_else(unresolved)?,
+                _ => return Err(unresolved()),
+            };
+            // Segments are sorted by offset: from the last one starting at or before `start`, up to `end`
+            let first_index = segments
+                .partition_point(|segment| segment.offset <= start)
+                .saturating_sub(1);
+            let end_index = segments.partition_point(|segment| segment.offset < end);
+            let covering = segments.get(first_index..end_index).unwrap_or_default();
+            let (Some(first), Some(last)) = (covering.first(), covering.last()) else {
+                return Err(unresolved());
+            };
+            if first.offset > start
+                || first.offset + first.length <= start
+                || last.offset + last.length < end
+                || covering
+                    .windows(2)
+                    .any(|w| w[0].offset + w[0].length != w[1].offset)
+            {
+                return Err(unresolved());
+            }
+
+            // Parts of the first and last segments outside of the interval, in the step orientation
+            let (mut before, mut after) = (start - first.offset, last.offset + last.length - end);
+            if step.is_reverse {
+                std::mem::swap(&mut before, &mut after);
+            }
+            // Only the ends of the whole path can fall inside a segment
+            if (i > 0 && before > 0) || (i + 1 < self.path.len() && after > 0) {
+                return Err(unresolved());
+            }
+            if i == 0 {
+                path_offset = before;
+            }
+
+            let mut steps: Vec<_> = covering
+                .iter()
+                .map(|segment| GafStep {
+                    name: graph.node_to_segment(segment.node_id),
+                    is_reverse: step.is_reverse,
+                    is_stable: false,
+                    is_interval: false,
+                    start: None,
+                    end: None,
+                })
+                .collect();
+            if step.is_reverse {
+                steps.reverse();
+            }
+            path_length += covering.iter().map(|segment| segment.length).sum::<i64>();
+            record.path.extend(steps);
+        }
+
+        record.path_length = path_length;
+        record.path_start += path_offset;
+        record.path_end += path_offset;
+        Ok(record)
+    }
+
+    /**
+     * Copy of the record where the segments are replaced by the nodes of vg they were chopped
+     * into, named by their IDs. Nodes of chopped segments outside of the alignment are left out,
+     * and the path coordinates are shifted to the resulting path of nodes.
+     */
+    pub fn segments_to_nodes(&self, graph: &GraphIndex) -> Result<GafRecord, ConversionError> {
+        let mut record = self.clone();
+        record.path.clear();
+        let mut path_length = 0;
+        // Start of the current node on the path
+        let mut offset = 0;
+        // Length of the nodes left out before the alignment
+        let mut skipped = 0;
+        for step in &self.path {
+            let mut nodes = graph.segment_to_nodes(&step.name)?;
+            if step.is_reverse {
+                nodes.reverse();
+            }
+            let is_chopped = nodes.len() > 1;
+            for node_id in nodes {
+                let length = graph.node_to_length(node_id)? as i64;
+                let (start, end) = (offset, offset + length);
+                offset = end;
+                if is_chopped
+                    && self.path_start < self.path_end
+                    && (end <= self.path_start || start >= self.path_end)
+                {
+                    if end <= self.path_start {
+                        skipped += length;
+                    }
+                    continue;
+                }
+                path_length += length;
+                record.path.push(GafStep {
+                    name: node_id.to_string(),
+                    is_reverse: step.is_reverse,
+                    is_stable: false,
+                    is_interval: false,
+                    start: None,
+                    end: None,
+                });
+            }
+        }
+
+        record.path_length = path_length;
+        record.path_start -= skipped;
+        record.path_end -= skipped;
+        Ok(record)
+    }
+
+    /**
+     * Record where consecutive nodes of vg coming from the same chopped segment
+     * are merged into this segment, with the path coordinates on the segments.
+     * Only the first and last segments of the path can be partially covered by its nodes.
+     */
+    pub fn nodes_to_segments(mut self, graph: &GraphIndex) -> Result<GafRecord, ConversionError> {
+        let translation = graph.translation();
+        if translation.is_empty() {
+            return Ok(self);
+        }
+        let path = std::mem::take(&mut self.path);
+        let mut path_length = 0;
+        // Length of the first segment before the first node
+        let mut path_offset = 0;
+        // Segment of the previous step, its orientation, the rank of the node and the node count
+        let mut previous: Option<(&str, bool, usize, usize)> = None;
+        for step in &path {
+            let node_id = step.name.parse()?;
+            let (name, rank) = translation
+                .segment(node_id)
+                .ok_or(ConversionError::MissingNode(node_id))?;
+            let nodes = translation.nodes(name).unwrap_or_default();
+            // Rank of the node in the orientation of the step
+            let rank = if step.is_reverse {
+                nodes.len() - 1 - rank
+            } else {
+                rank
+            };
+            match previous {
+                Some((previous_name, is_reverse, previous_rank, _))
+                    if previous_name == name
+                        && is_reverse == step.is_reverse
+                        && previous_rank + 1 == rank => {}
+                _ => {
+                    if let Some((previous_name, _, previous_rank, count)) = previous {
+                        if previous_rank + 1 != count {
+                            return Err(ConversionError::IncompleteSegment(
+                                previous_name.to_string(),
+                            ));
                         }
-                        '-' => {
-                            let target = &cs[1..];
-                            Cigar {
-                                cat: first_char.into(),
-                                length: target.len(),
-                                query: "".into(),
-                                target: target.into(),
-                            }
+                    }
+                    if rank > 0 {
+                        if previous.is_some() {
+                            return Err(ConversionError::IncompleteSegment(name.to_string()));
+                        }
+                        let skipped = if step.is_reverse {
+                            &nodes[nodes.len() - rank..]
+                        } else {
+                            &nodes[..rank]
+                        };
+                        for node_id in skipped {
+                            path_offset += graph.node_to_length(*node_id)? as i64;
                         }
-                        '*' => Cigar {
-                            cat: first_char.into(),
-                            length: 1,
-                            query: cs[2..3].into(),
-                            target: cs[1..2].into(),
-                        },
-                        _ => unreachable!(),
                     }
+                    path_length += graph.segment_to_length(name)? as i64;
+                    self.path.push(GafStep {
+                        name: name.to_string(),
+                        ..step.clone()
+                    });
+                }
+            }
+            previous = Some((name, step.is_reverse, rank, nodes.len()));
+        }
+
+        self.path_length = path_length;
+        self.path_start += path_offset;
+        self.path_end += path_offset;
+        Ok(self)
+    }
+
+    /**
+     * Operations of the alignment, from the `cs` field if present, otherwise from the `cg` field
+     */
+    pub fn iter_cigar(&self) -> Result<Vec<Cigar>, GafError> {
+        if self.opt_fields.contains_key("cs") {
+            Ok(self
+                .iter_cs()?
+                .into_iter()
+                .map(|op| match op {
+                    CsOp::Match(length) => Cigar {
+                        cat: ':',
+                        length,
+                        query: "".into(),
+                        target: "".into(),
+                    },
+                    CsOp::Identical(bases) => Cigar {
+                        cat: ':',
+                        length: bases.len(),
+                        query: bases.clone(),
+                        target: bases,
+                    },
+                    CsOp::Substitution { target, query } => Cigar {
+                        cat: '*',
+                        length: 1,
+                        query: query.into(),
+                        target: target.into(),
+                    },
+                    CsOp::Insertion(query) => Cigar {
+                        cat: '+',
+                        length: query.len(),
+                        query,
+                        target: "".into(),
+                    },
+                    CsOp::Deletion(target) => Cigar {
+                        cat: '-',
+                        length: target.len(),
+                        query: "".into(),
+                        target,
+                    },
+                    CsOp::Intron { length, .. } => Cigar {
+                        cat: '~',
+                        length,
+                        query: "".into(),
+                        target: "".into(),
+                    },
                 })
-                .collect()
+                .collect())
         } else {
             self.iter_cg()
         }
     }
 
-    pub fn iter_cs(&self) -> Vec<&str> {
-        let Some(cigar_pair) = self.opt_fields.get("cs") else { return vec![]; };
-        let cs_cigar = &cigar_pair.1;
-        let mut splits = cs_cigar
-            .match_indices([':', '*', '-', '+'])
-            .map(|(i, _)| i)
-            .collect::<Vec<_>>();
-        splits.push(cs_cigar.len());
-        splits
-            .windows(2)
-            .map(|indexes| &cs_cigar[indexes[0]..indexes[1]])
-            .collect()
-    }
-
-    pub fn iter_cg(&self) -> Vec<Cigar> {
-        let Some(cigar_pair) = self.opt_fields.get("cg") else { return vec![]; };
-        let cg_cigar = &cigar_pair.1;
-        let mut splits = cg_cigar
-            .match_indices(['M', 'I', 'D', 'N', 'S', 'H', 'P', 'X', '='])
-            .map(|(i, _)| i)
-            .collect::<Vec<_>>();
-        splits.push(cg_cigar.len());
-        splits
-            .windows(2)
-            .map(|indexes| {
-                let cat = cg_cigar[indexes[0]..indexes[1]].chars().next().unwrap();
-                Cigar {
-                    length: 1,
-                    cat,
-                    query: "".into(),
-                    target: "".into(),
-                }
-            })
-            .collect()
+    /**
+     * Operations of the `cs` field, in short or long form
+     */
+    pub fn iter_cs(&self) -> Result<Vec<CsOp>, GafError> {
+        Ok(self.cs()?.map(|cs| cs.ops).unwrap_or_default())
+    }
+
+    /**
+     * Operations of the `cg` field, with their run lengths.
+     * The lengths consumed on the query and on the path must match the record coordinates,
+     * unless these are missing. Clipped bases are outside of the query interval.
+     */
+    pub fn iter_cg(&self) -> Result<Vec<Cigar>, GafError> {
+        let Some((_, cg_cigar)) = self.opt_fields.get("cg") else {
+            return Ok(vec![]);
+        };
+        let invalid = || GafError::InvalidCigar(Location::new("cg", cg_cigar));
+        let mut cigars = vec![];
+        let mut rest = cg_cigar.as_str();
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let length = rest[..digits].parse::<usize>().map_err(|_| invalid())?;
+            let cat = rest[digits..].chars().next().ok_or_else(invalid)?;
+            if !matches!(cat, 'M' | 'I' | 'D' | 'N' | 'S' | 'H' | 'P' | '=' | 'X') || length == 0 {
+                return Err(invalid());
+            }
+            cigars.push(Cigar {
+                cat,
+                length,
+                query: "".into(),
+                target: "".into(),
+            });
+            rest = &rest[digits + 1..];
+        }
+
+        let query_length: usize = cigars
+            .iter()
+            .filter(|cigar| matches!(cigar.cat, 'M' | 'I' | '=' | 'X'))
+            .map(|cigar| cigar.length)
+            .sum();
+        let path_length: usize = cigars
+            .iter()
+            .filter(|cigar| matches!(cigar.cat, 'M' | 'D' | 'N' | '=' | 'X'))
+            .map(|cigar| cigar.length)
+            .sum();
+        let coordinates = [
+            self.query_start,
+            self.query_end,
+            self.path_start,
+            self.path_end,
+        ];
+        if !coordinates.contains(&MISSING_INT)
+            && (query_length as i64 != self.query_end - self.query_start
+                || path_length as i64 != self.path_end - self.path_start)
+        {
+            return Err(GafError::CigarMismatch(Location::new("cg", cg_cigar)));
+        }
+        Ok(cigars)
     }
 
     pub fn convert_from_gam(
         value: &vg::Alignment,
-        graph: &GFA<usize, ()>,
+        graph: &GraphIndex,
     ) -> Result<Self, ConversionError> {
         let mut query_name = value.name.clone();
         if query_name.is_empty() {
@@ -413,9 +1018,8 @@ impl GafRecord {
                         .ok_or(ConversionError::MissingPosition)?;
                     let start_offset_on_node = position.offset;
                     let mut offset = start_offset_on_node;
-                    let node_to_segment_offset = 0;
-                    let node_length = graph.node_to_length(position.node_id);
-                    let mut node_seq = "".to_string();
+                    let node_length = graph.node_to_length(position.node_id)?;
+                    let mut node_seq = "";
                     let mut skip_step = false;
                     let mut _prev_range = (0, false, 0, 0);
 
@@ -431,8 +1035,8 @@ impl GafRecord {
                             skip_step = true;
                         } else {
                             if node_seq.is_empty() {
-                                node_seq =
-                                    graph.node_to_sequence(position.node_id, position.is_reverse);
+                                node_seq = graph
+                                    .node_to_sequence(position.node_id, position.is_reverse)?;
                             }
 
                             let mut del_start_offset = 0;
@@ -447,8 +1051,12 @@ impl GafRecord {
                                 if !running_deletion {
                                     cs_cigar_str += "-";
                                 }
-                                cs_cigar_str += &node_seq[del_start_offset as usize
-                                    ..(start_offset_on_node - del_start_offset) as usize];
+                                cs_cigar_str += node_slice(
+                                    node_seq,
+                                    position.node_id,
+                                    del_start_offset,
+                                    start_offset_on_node,
+                                )?;
                                 running_deletion = true;
                             }
                         }
@@ -468,28 +1076,36 @@ impl GafRecord {
                             if edit.is_sub() {
                                 if node_seq.is_empty() {
                                     node_seq = graph
-                                        .node_to_sequence(position.node_id, position.is_reverse);
+                                        .node_to_sequence(position.node_id, position.is_reverse)?;
                                 }
 
                                 for i in 0..edit.from_length as i64 {
                                     cs_cigar_str += "*";
-                                    cs_cigar_str +=
-                                        &node_seq[(offset + i) as usize..(offset + i + 1) as usize];
+                                    cs_cigar_str += node_slice(
+                                        node_seq,
+                                        position.node_id,
+                                        offset + i,
+                                        offset + i + 1,
+                                    )?;
                                     cs_cigar_str += &edit.sequence[i as usize..i as usize + 1];
                                 }
                                 running_deletion = false;
                             } else if edit.is_deletion() {
                                 if node_seq.is_empty() {
                                     node_seq = graph
-                                        .node_to_sequence(position.node_id, position.is_reverse);
+                                        .node_to_sequence(position.node_id, position.is_reverse)?;
                                 }
 
                                 if !running_deletion {
                                     cs_cigar_str += "-";
                                 }
 
-                                cs_cigar_str += &node_seq
-                                    [offset as usize..(offset + edit.from_length as i64) as usize];
+                                cs_cigar_str += node_slice(
+                                    node_seq,
+                                    position.node_id,
+                                    offset,
+                                    offset + edit.from_length as i64,
+                                )?;
                                 running_deletion = true;
                             } else if edit.is_insertion() {
                                 cs_cigar_str += "+";
@@ -528,8 +1144,8 @@ impl GafRecord {
                             || position.is_reverse != next_position.is_reverse
                         {
                             if node_seq.is_empty() {
-                                node_seq =
-                                    graph.node_to_sequence(position.node_id, position.is_reverse);
+                                node_seq = graph
+                                    .node_to_sequence(position.node_id, position.is_reverse)?;
                             }
                             if running_match_length > 0 {
                                 cs_cigar_str += ":";
@@ -539,7 +1155,8 @@ impl GafRecord {
                             if !running_deletion {
                                 cs_cigar_str += "-";
                             }
-                            cs_cigar_str += &node_seq[offset as usize..];
+                            cs_cigar_str +=
+                                node_slice(node_seq, position.node_id, offset, node_length as i64)?;
                             running_deletion = true;
                         } else {
                             skip_step = true;
@@ -562,7 +1179,8 @@ impl GafRecord {
                     if i == path.mapping.len() - 1 {
                         gaf.path_end = gaf.path_start;
 
-                        let offset_on_path_visit = offset + node_to_segment_offset;
+                        // offsets are on the nodes, nodes_to_segments moves them to the segments
+                        let offset_on_path_visit = offset;
                         if gaf.path_length > offset_on_path_visit {
                             gaf.path_end =
                                 gaf.path_length - 1 - (node_length as i64 - offset_on_path_visit);
@@ -618,8 +1236,10 @@ impl GafRecord {
                                 ("b".to_string(), is_properly_paired.to_string()),
                             );
                         }
-                        if let Some(Kind::StringValue(support)) =
-                            annotation.fields["support"].kind.as_ref()
+                        if let Some(Kind::StringValue(support)) = annotation
+                            .fields
+                            .get("support")
+                            .and_then(|support| support.kind.as_ref())
                         {
                             gaf.opt_fields
                                 .insert("AD".to_string(), ("i".to_string(), support.clone()));
@@ -636,7 +1256,8 @@ impl GafRecord {
             gaf.opt_fields
                 .insert("fp".to_string(), ("Z".to_string(), fragment_prev.name));
         }
-        Ok(gaf)
+        // Steps are on the nodes of vg, which may have been chopped from segments
+        gaf.nodes_to_segments(graph)
     }
 }
 
@@ -656,7 +1277,7 @@ pub struct Cigar {
 mod tests {
     use super::*;
     use crate::{convert_gam_to_gaf, gam};
-    use gfa::parser::GFAParser;
+    use gfa::{gfa::GFA, parser::GFAParser};
 
     #[test]
     fn gaf_read() -> Result<(), Box<dyn std::error::Error>> {
@@ -665,6 +1286,155 @@ mod tests {
         assert_eq!(rec.query_name, "read2");
         assert_eq!(rec.query_length, 7);
         assert_eq!(rec.strand, '-');
+        assert_eq!(rec.path[0].name, "chr1");
+        assert_eq!((rec.path[1].start, rec.path[1].end), (Some(8), Some(16)));
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_parse_errors() {
+        let line = "read2\t7\t0\t7\t-\t>chr1:5-8>foo:8-16\t11\t1\t8\t7\t7\t60\tcg:Z:7M";
+        for (bad_line, column, text) in [
+            (line.replace("\t-\t", "\t\t"), "strand", ""),
+            (line.replace("\t-\t", "\tx\t"), "strand", "x"),
+            (line.replace("5-8", "5"), "path", ">chr1:5"),
+            (line.replace("\t11\t", "\tx\t"), "path length", "x"),
+            (line.replace("cg:Z", "c:Z"), "tag", "c:Z:7M"),
+            (format!("{}\tcg:Z:1M", line), "tag", "cg:Z:1M"),
+            (line[..13].to_string(), "path", &line[..13]),
+        ] {
+            let data = format!("{}\n{}\n", line, bad_line);
+            let e = parse(data.as_bytes()).unwrap_err();
+            let location = e.location().expect("parse error without location");
+            assert_eq!(location.line, Some(2));
+            assert_eq!(location.column, column);
+            assert_eq!(location.text, text);
+        }
+    }
+
+    #[test]
+    fn gaf_cg() -> Result<(), Box<dyn std::error::Error>> {
+        let line = "read1\t12\t2\t11\t+\t>1>2\t20\t3\t11\t7\t9\t60\tcg:Z:2S3M3I1X1D2=1N1H";
+        let record = GafRecord::parse(line)?;
+        let ops: Vec<_> = record
+            .iter_cigar()?
+            .iter()
+            .map(|cigar| (cigar.length, cigar.cat))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (2, 'S'),
+                (3, 'M'),
+                (3, 'I'),
+                (1, 'X'),
+                (1, 'D'),
+                (2, '='),
+                (1, 'N'),
+                (1, 'H')
+            ]
+        );
+
+        for (cg, kind) in [
+            ("3M5", "invalid cigar"),
+            ("M8", "invalid cigar"),
+            ("3Q5M", "invalid cigar"),
+            ("0M8M", "invalid cigar"),
+            ("9M", "cigar mismatch"),
+            ("7M1D1I", "cigar mismatch"),
+        ] {
+            let line = format!(
+                "read1\t12\t2\t11\t+\t>1>2\t20\t3\t11\t7\t9\t60\tcg:Z:{}",
+                cg
+            );
+            let e = GafRecord::parse(&line)?.iter_cg().unwrap_err();
+            assert_eq!(e.kind(), kind, "{}", cg);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_lenient() -> Result<(), Box<dyn std::error::Error>> {
+        let line = "read2\t7\t0\t7\t-\t>chr1:5-8>foo:8-16\t11\t1\t8\t7\t7\t60\tcg:Z:7M";
+        // Trailing whitespace, blank line and truncated last line
+        let data = format!("{}\n{} \t\n\n{}", line, line, &line[..30]);
+
+        assert!(parse(data.as_bytes()).is_err());
+
+        let options = ParseOptions {
+            strictness: Strictness::SkipInvalid,
+        };
+        let (records, report) = parse_with_options(data.as_bytes(), options)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(report.skipped(), 3);
+        assert_eq!(report.counts["invalid tag"], 1);
+        assert_eq!(report.counts["missing token"], 1);
+        assert_eq!(report.counts["invalid interval"], 1);
+        assert_eq!(report.errors[2].location().unwrap().line, Some(4));
+
+        let options = ParseOptions {
+            strictness: Strictness::Repair,
+        };
+        let (records, report) = parse_with_options(data.as_bytes(), options)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], records[1]);
+        assert_eq!(report.repaired, 2);
+        assert_eq!(report.skipped(), 1);
+
+        // Only the first errors are kept
+        let data = "x\n".repeat(MAX_REPORTED_ERRORS + 50);
+        let options = ParseOptions {
+            strictness: Strictness::SkipInvalid,
+        };
+        let (records, report) = parse_with_options(data.as_bytes(), options)?;
+        assert!(records.is_empty());
+        assert_eq!(report.skipped(), MAX_REPORTED_ERRORS + 50);
+        assert_eq!(report.errors.len(), MAX_REPORTED_ERRORS);
+        assert_eq!(report.counts["missing token"], MAX_REPORTED_ERRORS + 50);
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read("data/convert.gaf")?;
+        let mut written = vec![];
+        write(&parse(&data[..])?, &mut written)?;
+        assert_eq!(String::from_utf8(written)?, String::from_utf8(data)?);
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let records = parse_from_file("data/convert.gaf")?;
+        let mut writer = Writer::new(vec![]);
+        for record in &records {
+            writer.write_record(record)?;
+        }
+        let data = writer.finish()?;
+
+        // Windows line endings are accepted too
+        let data = String::from_utf8(data)?.replace('\n', "\r\n");
+        let streamed = Reader::new(data.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(streamed, records);
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_compressed() -> Result<(), Box<dyn std::error::Error>> {
+        let records = parse_from_file("data/convert.gaf")?;
+        for compression in [Compression::Gzip(6), Compression::Bgzf(6)] {
+            let mut writer = Writer::with_compression(vec![], compression);
+            for record in &records {
+                writer.write_record(record)?;
+            }
+            let data = writer.finish()?;
+            assert_eq!(parse(&data[..])?, records);
+        }
+
+        let dir = tempfile::tempdir()?;
+        let out_file = dir.path().join("convert.out.gaf.gz");
+        write_to_file(&records, &out_file)?;
+        assert_eq!(parse_from_file(&out_file)?, records);
         Ok(())
     }
 
@@ -672,6 +1442,7 @@ mod tests {
     fn convert_from_gam() -> Result<(), Box<dyn std::error::Error>> {
         use pretty_assertions::assert_eq;
         let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/convert.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
         let gam = gam::parse_from_file("data/convert.gam")?;
         let gaf = parse_from_file("data/convert.gaf")?;
 
@@ -690,4 +1461,24 @@ mod tests {
         assert_eq!(gaf.len(), match_count);
         Ok(())
     }
+
+    #[test]
+    fn convert_pair_annotation() -> Result<(), Box<dyn std::error::Error>> {
+        use prost_types::{Struct, Value};
+        let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/convert.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
+        let mut alignment = gam::parse_from_file("data/convert.gam")?.remove(0);
+
+        // A properly paired read without a support annotation
+        let proper_pair = Value {
+            kind: Some(Kind::BoolValue(true)),
+        };
+        alignment.annotation = Some(Struct {
+            fields: [("proper_pair".to_string(), proper_pair)].into(),
+        });
+        let record = GafRecord::convert_from_gam(&alignment, &graph)?;
+        assert_eq!(record.tag_bool("pd")?, Some(true));
+        assert_eq!(record.tag("AD")?, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,255 @@
+use crate::{
+    framing::VirtualOffset,
+    gaf::{self, GafError, GafRecord},
+    gai::{self, GamIndex},
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, Write},
+};
+
+/**
+ * Node IDs of the steps of a record that are nodes of the graph.
+ * Steps on stable paths are not nodes, and neither are segments whose name is not a number.
+ */
+fn record_nodes(record: &GafRecord) -> impl Iterator<Item = i64> + '_ {
+    record
+        .path
+        .iter()
+        .filter(|step| !step.is_stable)
+        .filter_map(|step| step.name.parse().ok())
+}
+
+/**
+ * Key used to sort records: the smallest node ID of their path.
+ * Records without nodes (unmapped or on stable paths only) come first.
+ */
+fn sort_key(record: &GafRecord) -> i64 {
+    record_nodes(record).min().unwrap_or_default()
+}
+
+/**
+ * Sort records in memory by the smallest node ID of their path, keeping the input order for ties
+ */
+pub fn sort_records(records: &mut [GafRecord]) {
+    records.sort_by_cached_key(sort_key);
+}
+
+/**
+ * Region index of a bgzip-compressed GAF, in the spirit of tabix.
+ *
+ * Each record is assigned to a bin of node IDs, exactly as in `GamIndex` which holds the bins,
+ * so the file format is the same as a `.gxi`. Records should be sorted with `sort_records`
+ * for queries to read few blocks.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GafIndex {
+    nodes: GamIndex,
+}
+
+impl GafIndex {
+    pub fn index<R: Read>(reader: &mut gaf::Reader<R>) -> Result<Self, GafError> {
+        let mut nodes = GamIndex::default();
+        let mut start = reader.virtual_position()?;
+        while let Some(record) = reader.read_record()? {
+            let end = reader.virtual_position()?;
+            let mut ids = record_nodes(&record);
+            if let Some(first) = ids.next() {
+                let (min_id, max_id) = ids.fold((first, first), |(min, max), node_id| {
+                    (min.min(node_id), max.max(node_id))
+                });
+                nodes.add_group(min_id, max_id, start, end);
+            }
+            start = end;
+        }
+        Ok(Self { nodes })
+    }
+
+    pub fn index_file(path: impl AsRef<std::path::Path>) -> Result<Self, GafError> {
+        let f = File::open(path)?;
+        Self::index(&mut gaf::Reader::new(f))
+    }
+
+    /**
+     * Ranges of virtual offsets, sorted and disjoint, holding all the records
+     * that may touch nodes from `min_id` to `max_id` (inclusive)
+     */
+    pub fn find(&self, min_id: i64, max_id: i64) -> Vec<(VirtualOffset, VirtualOffset)> {
+        self.nodes.find(min_id, max_id)
+    }
+
+    pub fn parse(data: impl Read) -> Result<Self, GafError> {
+        let nodes = GamIndex::parse(data)?;
+        Ok(Self { nodes })
+    }
+
+    pub fn parse_from_file(path: impl AsRef<std::path::Path>) -> Result<Self, GafError> {
+        let f = File::open(path)?;
+        Self::parse(f)
+    }
+
+    pub fn write(&self, out_file: impl Write) -> Result<(), GafError> {
+        Ok(self.nodes.write(out_file)?)
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<std::path::Path>) -> Result<(), GafError> {
+        let f = File::create(path)?;
+        self.write(f)
+    }
+}
+
+/**
+ * Reader of a bgzip-compressed GAF with its region index
+ */
+pub struct IndexedReader<R: Read + Seek> {
+    reader: gaf::Reader<R>,
+    index: GafIndex,
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    pub fn new(data: R, index: GafIndex) -> Self {
+        Self {
+            reader: gaf::Reader::new(data),
+            index,
+        }
+    }
+
+    /**
+     * Iterate over the records whose path touches nodes from `min_id` to `max_id` (inclusive)
+     */
+    pub fn query(&mut self, min_id: i64, max_id: i64) -> Query<'_, R> {
+        Query {
+            ranges: self.index.find(min_id, max_id).into(),
+            reader: &mut self.reader,
+            min_id,
+            max_id,
+            end: None,
+        }
+    }
+}
+
+impl IndexedReader<File> {
+    /**
+     * Open a bgzip-compressed GAF and its index, stored next to it with a `.gxi` extension appended
+     */
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, GafError> {
+        let mut index_path = path.as_ref().as_os_str().to_owned();
+        index_path.push(".");
+        index_path.push(gai::EXTENSION);
+        let index = GafIndex::parse_from_file(index_path)?;
+        Ok(Self::new(File::open(path)?, index))
+    }
+}
+
+/**
+ * Iterator over the records of an indexed GAF touching a range of nodes
+ */
+pub struct Query<'a, R: Read + Seek> {
+    reader: &'a mut gaf::Reader<R>,
+    ranges: VecDeque<(VirtualOffset, VirtualOffset)>,
+    min_id: i64,
+    max_id: i64,
+    // End of the range being read
+    end: Option<VirtualOffset>,
+}
+
+impl<'a, R: Read + Seek> Query<'a, R> {
+    fn read_record(&mut self) -> Result<Option<GafRecord>, GafError> {
+        loop {
+            match self.end {
+                Some(end) if self.reader.virtual_position()? < end => (),
+                _ => {
+                    let Some((start, end)) = self.ranges.pop_front() else {
+                        return Ok(None);
+                    };
+                    self.reader.seek_virtual(start)?;
+                    self.end = Some(end);
+                }
+            }
+            let Some(record) = self.reader.read_record()? else {
+                return Ok(None);
+            };
+            if record_nodes(&record).any(|id| self.min_id <= id && id <= self.max_id) {
+                return Ok(Some(record));
+            }
+        }
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for Query<'a, R> {
+    type Item = Result<GafRecord, GafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::Compression;
+    use std::io::Cursor;
+
+    #[test]
+    fn gaf_seek_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let line = "read\t7\t0\t7\t+\t>1>2\t11\t1\t8\t7\t7\t60";
+        let mut writer =
+            crate::bgzf::Writer::with_threads(vec![], flate2::Compression::default(), 1);
+        write!(writer, "{}\n{}\n{}\tx\n", line, line, line)?;
+        let data = writer.finish()?;
+
+        let mut reader = gaf::Reader::new(Cursor::new(data));
+        reader.read_record()?;
+        let second = reader.virtual_position()?;
+        reader.read_record()?;
+        let e = reader.read_record().unwrap_err();
+        assert_eq!(e.location().unwrap().line, Some(3));
+
+        // The line numbers are lost in the middle of the file, found again at its start
+        reader.seek_virtual(second)?;
+        reader.read_record()?;
+        let e = reader.read_record().unwrap_err();
+        assert_eq!(e.location().unwrap().line, None);
+        reader.seek_virtual(VirtualOffset::default())?;
+        reader.read_record()?;
+        reader.read_record()?;
+        let e = reader.read_record().unwrap_err();
+        assert_eq!(e.location().unwrap().line, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_query() -> Result<(), Box<dyn std::error::Error>> {
+        let mut records = gaf::parse_from_file("data/convert.gaf")?;
+        sort_records(&mut records);
+        assert!(records
+            .windows(2)
+            .all(|w| sort_key(&w[0]) <= sort_key(&w[1])));
+
+        let mut writer = gaf::Writer::with_compression(vec![], Compression::default());
+        for record in &records {
+            writer.write_record(record)?;
+        }
+        let data = writer.finish()?;
+        let index = GafIndex::index(&mut gaf::Reader::new(&data[..]))?;
+        let mut buf = vec![];
+        index.write(&mut buf)?;
+        assert_eq!(GafIndex::parse(&buf[..])?, index);
+
+        let (min_id, max_id) = (100, 120);
+        let expected: Vec<_> = records
+            .iter()
+            .filter(|r| record_nodes(r).any(|id| min_id <= id && id <= max_id))
+            .cloned()
+            .collect();
+        assert!(!expected.is_empty());
+
+        let mut reader = IndexedReader::new(Cursor::new(data), index);
+        let found = reader
+            .query(min_id, max_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(found, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use crate::gaf::{GafError, GafRecord, Location};
+use std::fmt;
+
+/**
+ * Value of a SAM-style optional field, as described here: https://samtools.github.io/hts-specs/SAMtags.pdf
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Char(char),      // A: printable character
+    Int(i64),        // i: signed integer
+    Float(f64),      // f: single-precision float, kept as f64
+    String(String),  // Z: printable string, spaces allowed
+    Hex(Vec<u8>),    // H: byte array written in hexadecimal
+    Array(TagArray), // B: array of numbers
+    Bool(bool),      // b: vg extension for booleans, written as 1 or 0
+}
+
+/**
+ * Numeric array of a `B` optional field, the variant gives the type of the elements
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagArray {
+    Int8(Vec<i8>),    // c
+    UInt8(Vec<u8>),   // C
+    Int16(Vec<i16>),  // s
+    UInt16(Vec<u16>), // S
+    Int32(Vec<i32>),  // i
+    UInt32(Vec<u32>), // I
+    Float(Vec<f32>),  // f
+}
+
+/**
+ * Check if `typ` is the type code of an optional field
+ */
+pub(crate) fn is_valid_type(typ: &str) -> bool {
+    matches!(typ, "A" | "i" | "f" | "Z" | "H" | "B" | "b")
+}
+
+impl TagValue {
+    /**
+     * Parse the value of an optional field of type `typ`
+     */
+    pub fn parse(typ: &str, value: &str) -> Result<Self, GafError> {
+        let invalid = || GafError::InvalidTag(Location::new("tag", &format!("{}:{}", typ, value)));
+        Ok(match typ {
+            "A" => {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => TagValue::Char(c),
+                    _ => return Err(invalid()),
+                }
+            }
+            "i" => TagValue::Int(value.parse().map_err(|_| invalid())?),
+            "f" => TagValue::Float(value.parse().map_err(|_| invalid())?),
+            "Z" => TagValue::String(value.to_string()),
+            "H" => {
+                // an odd number of digits leaves a last byte out of range
+                let bytes = (0..value.len())
+                    .step_by(2)
+                    .map(|i| {
+                        value
+                            .get(i..i + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?;
+                TagValue::Hex(bytes)
+            }
+            "B" => {
+                let mut items = value.split(',');
+                let subtype = items.next().ok_or_else(invalid)?;
+                let array = match subtype {
+                    "c" => TagArray::Int8(parse_items(items).ok_or_else(invalid)?),
+                    "C" => TagArray::UInt8(parse_items(items).ok_or_else(invalid)?),
+                    "s" => TagArray::Int16(parse_items(items).ok_or_else(invalid)?),
+                    "S" => TagArray::UInt16(parse_items(items).ok_or_else(invalid)?),
+                    "i" => TagArray::Int32(parse_items(items).ok_or_else(invalid)?),
+                    "I" => TagArray::UInt32(parse_items(items).ok_or_else(invalid)?),
+                    "f" => TagArray::Float(parse_items(items).ok_or_else(invalid)?),
+                    _ => return Err(invalid()),
+                };
+                TagValue::Array(array)
+            }
+            "b" => match value {
+                "1" | "true" => TagValue::Bool(true),
+                "0" | "false" => TagValue::Bool(false),
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        })
+    }
+
+    /**
+     * Type code of the value in the GAF
+     */
+    pub fn type_code(&self) -> &'static str {
+        match self {
+            TagValue::Char(_) => "A",
+            TagValue::Int(_) => "i",
+            TagValue::Float(_) => "f",
+            TagValue::String(_) => "Z",
+            TagValue::Hex(_) => "H",
+            TagValue::Array(_) => "B",
+            TagValue::Bool(_) => "b",
+        }
+    }
+}
+
+fn parse_items<'a, T: std::str::FromStr>(items: impl Iterator<Item = &'a str>) -> Option<Vec<T>> {
+    items.map(|item| item.parse().ok()).collect()
+}
+
+fn write_items<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    subtype: &str,
+    items: &[T],
+) -> fmt::Result {
+    write!(f, "{}", subtype)?;
+    for item in items {
+        write!(f, ",{}", item)?;
+    }
+    Ok(())
+}
+
+/**
+ * Value as written in the GAF, without the type code
+ */
+impl fmt::Display for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagValue::Char(c) => write!(f, "{}", c),
+            TagValue::Int(i) => write!(f, "{}", i),
+            TagValue::Float(x) => write!(f, "{}", x),
+            TagValue::String(s) => write!(f, "{}", s),
+            TagValue::Hex(bytes) => bytes.iter().try_for_each(|byte| write!(f, "{:02X}", byte)),
+            TagValue::Array(TagArray::Int8(items)) => write_items(f, "c", items),
+            TagValue::Array(TagArray::UInt8(items)) => write_items(f, "C", items),
+            TagValue::Array(TagArray::Int16(items)) => write_items(f, "s", items),
+            TagValue::Array(TagArray::UInt16(items)) => write_items(f, "S", items),
+            TagValue::Array(TagArray::Int32(items)) => write_items(f, "i", items),
+            TagValue::Array(TagArray::UInt32(items)) => write_items(f, "I", items),
+            TagValue::Array(TagArray::Float(items)) => write_items(f, "f", items),
+            TagValue::Bool(b) => write!(f, "{}", *b as u8),
+        }
+    }
+}
+
+impl GafRecord {
+    /**
+     * Typed value of the optional field `key`, if present
+     */
+    pub fn tag(&self, key: &str) -> Result<Option<TagValue>, GafError> {
+        self.opt_fields
+            .get(key)
+            .map(|(typ, value)| TagValue::parse(typ, value))
+            .transpose()
+    }
+
+    /**
+     * Set the optional field `key`, replacing any previous value
+     */
+    pub fn set_tag(&mut self, key: &str, value: TagValue) {
+        self.opt_fields.insert(
+            key.to_string(),
+            (value.type_code().to_string(), value.to_string()),
+        );
+    }
+
+    /**
+     * Value of the integer field `key`, an error if the field has another type
+     */
+    pub fn tag_i64(&self, key: &str) -> Result<Option<i64>, GafError> {
+        match self.tag(key)? {
+            Some(TagValue::Int(i)) => Ok(Some(i)),
+            Some(_) => Err(self.wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Value of the float field `key`, integers are converted
+     */
+    pub fn tag_f64(&self, key: &str) -> Result<Option<f64>, GafError> {
+        match self.tag(key)? {
+            Some(TagValue::Float(x)) => Ok(Some(x)),
+            Some(TagValue::Int(i)) => Ok(Some(i as f64)),
+            Some(_) => Err(self.wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Value of the string field `key`, without copy
+     */
+    pub fn tag_str(&self, key: &str) -> Result<Option<&str>, GafError> {
+        match self.opt_fields.get(key) {
+            Some((typ, value)) if typ == "Z" => Ok(Some(value)),
+            Some(_) => Err(self.wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Value of the character field `key`
+     */
+    pub fn tag_char(&self, key: &str) -> Result<Option<char>, GafError> {
+        match self.tag(key)? {
+            Some(TagValue::Char(c)) => Ok(Some(c)),
+            Some(_) => Err(self.wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Value of the boolean field `key`
+     */
+    pub fn tag_bool(&self, key: &str) -> Result<Option<bool>, GafError> {
+        match self.tag(key)? {
+            Some(TagValue::Bool(b)) => Ok(Some(b)),
+            Some(_) => Err(self.wrong_type(key)),
+            None => Ok(None),
+        }
+    }
+
+    fn wrong_type(&self, key: &str) -> GafError {
+        let (typ, value) = &self.opt_fields[key];
+        GafError::InvalidTag(Location::new("tag", &format!("{}:{}:{}", key, typ, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_values() -> Result<(), Box<dyn std::error::Error>> {
+        for (typ, value) in [
+            ("A", "x"),
+            ("i", "-42"),
+            ("f", "0.2183"),
+            ("Z", "some text"),
+            ("H", "1AE301"),
+            ("B", "c,-1,2"),
+            ("B", "f,0.5,1"),
+            ("b", "1"),
+        ] {
+            let tag = TagValue::parse(typ, value)?;
+            assert_eq!(tag.type_code(), typ);
+            assert_eq!(tag.to_string(), value);
+        }
+        for (typ, value) in [
+            ("A", "xy"),
+            ("i", "1.5"),
+            ("H", "1AE"),
+            ("B", "x,1"),
+            ("Q", "1"),
+        ] {
+            assert!(TagValue::parse(typ, value).is_err());
+        }
+
+        let line = "read2\t7\t0\t7\t-\t>1>2\t11\t1\t8\t7\t7\t60\tAS:i:160\tdv:f:0.5\tcs:Z::7";
+        let mut record = GafRecord::parse(line)?;
+        assert_eq!(record.tag_i64("AS")?, Some(160));
+        assert_eq!(record.tag_f64("dv")?, Some(0.5));
+        assert_eq!(record.tag_str("cs")?, Some(":7"));
+        assert_eq!(record.tag_i64("NM")?, None);
+        assert!(record.tag_i64("dv").is_err());
+
+        record.set_tag("NM", TagValue::Int(3));
+        assert_eq!(record.opt_fields["NM"], ("i".to_string(), "3".to_string()));
+        Ok(())
+    }
+}
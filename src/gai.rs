@@ -0,0 +1,392 @@
+use crate::{
+    framing::{FramingError, VirtualOffset},
+    gam, vg,
+};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use prost::encoding::encode_varint;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{prelude::*, BufReader, ErrorKind},
+};
+
+/**
+ * Extension of the index files, appended to the name of the indexed file
+ */
+pub const EXTENSION: &str = "gxi";
+const MAGIC_BYTES: &[u8] = b"GXI!";
+const VERSION: u32 = 1;
+// Each window spans 2^WINDOW_SHIFT node IDs
+const WINDOW_SHIFT: u32 = 8;
+const NO_START: u64 = i64::MAX as u64;
+
+/**
+ * Index of a node-sorted, BGZF-compressed GAM file, stored with the `.gxi` extension.
+ * The layout follows the binning scheme of vg, but the file format is specific to this crate:
+ * `vg gamsort -i` indexes cannot be read and vg cannot read these.
+ *
+ * Groups of alignments are assigned to the bin of a binary trie over the node IDs that
+ * contains all of their nodes, and each bin records the ranges of virtual offsets of its groups.
+ * Fixed-size windows of node IDs record the first virtual offset that can touch them.
+ *
+ * The file is a gzipped stream of varints: the magic bytes "GXI!", the version, the number
+ * of bins, then for each bin its number, its number of ranges and their start and end offsets,
+ * and finally the number of windows followed by the start offset of each window.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GamIndex {
+    bin_to_ranges: BTreeMap<u64, Vec<(VirtualOffset, VirtualOffset)>>,
+    window_to_start: Vec<u64>,
+}
+
+impl GamIndex {
+    /**
+     * Index a BGZF-compressed GAM, sorted by node ID for the index to be effective
+     */
+    pub fn index<R: Read>(reader: &mut gam::Reader<R>) -> Result<Self, FramingError> {
+        let mut index = Self::default();
+        let mut start = reader.virtual_position()?;
+        while let Some(group) = reader.read_group()? {
+            let end = reader.virtual_position()?;
+            let mut nodes = group.iter().flat_map(alignment_nodes);
+            if let Some(first) = nodes.next() {
+                let (min_id, max_id) = nodes.fold((first, first), |(min, max), node_id| {
+                    (min.min(node_id), max.max(node_id))
+                });
+                index.add_group(min_id, max_id, start, end);
+            }
+            start = end;
+        }
+        Ok(index)
+    }
+
+    pub fn index_file(path: impl AsRef<std::path::Path>) -> Result<Self, FramingError> {
+        let f = File::open(path)?;
+        Self::index(&mut gam::Reader::new(f))
+    }
+
+    /**
+     * Record a group of alignments touching nodes from `min_id` to `max_id` (inclusive),
+     * stored between the virtual offsets `start` and `end`
+     */
+    pub fn add_group(
+        &mut self,
+        min_id: i64,
+        max_id: i64,
+        start: VirtualOffset,
+        end: VirtualOffset,
+    ) {
+        let ranges = self
+            .bin_to_ranges
+            .entry(common_bin(min_id as u64, max_id as u64))
+            .or_default();
+        match ranges.last_mut() {
+            // Extend the last range if this group comes right after it
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+
+        let min_window = (min_id as u64 >> WINDOW_SHIFT) as usize;
+        let max_window = (max_id as u64 >> WINDOW_SHIFT) as usize;
+        if self.window_to_start.len() <= max_window {
+            self.window_to_start.resize(max_window + 1, NO_START);
+        }
+        for window_start in &mut self.window_to_start[min_window..=max_window] {
+            *window_start = (*window_start).min(start.into());
+        }
+    }
+
+    /**
+     * Ranges of virtual offsets, sorted and disjoint, holding all the groups
+     * that may touch nodes from `min_id` to `max_id` (inclusive)
+     */
+    pub fn find(&self, min_id: i64, max_id: i64) -> Vec<(VirtualOffset, VirtualOffset)> {
+        let (min_id, max_id) = (min_id as u64, max_id as u64);
+        // Nothing before the first group touching the windows of the query can be relevant
+        let min_window = (min_id >> WINDOW_SHIFT) as usize;
+        let max_window = (max_id >> WINDOW_SHIFT) as usize;
+        let min_start = self
+            .window_to_start
+            .get(min_window..=max_window.min(self.window_to_start.len().saturating_sub(1)))
+            .and_then(|windows| windows.iter().min().copied())
+            .unwrap_or(NO_START);
+        if min_start == NO_START {
+            return vec![];
+        }
+
+        let mut ranges: Vec<_> = self
+            .bin_to_ranges
+            .iter()
+            .filter(|(&bin, _)| {
+                let (bin_min, bin_max) = bin_range(bin);
+                bin_min <= max_id && min_id <= bin_max
+            })
+            .flat_map(|(_, ranges)| ranges)
+            .filter(|(_, end)| u64::from(*end) > min_start)
+            .copied()
+            .collect();
+        ranges.sort();
+
+        // Merge overlapping ranges
+        let mut merged: Vec<(VirtualOffset, VirtualOffset)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    /**
+     * Iterate over the alignments touching nodes from `min_id` to `max_id` (inclusive)
+     */
+    pub fn query<'a, R: Read + Seek>(
+        &self,
+        reader: &'a mut gam::Reader<R>,
+        min_id: i64,
+        max_id: i64,
+    ) -> Query<'a, R> {
+        Query {
+            reader,
+            ranges: self.find(min_id, max_id).into(),
+            min_id,
+            max_id,
+            end: None,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub fn parse(data: impl Read) -> Result<Self, FramingError> {
+        let mut data = BufReader::new(MultiGzDecoder::new(data));
+        let mut magic = [0; 4];
+        data.read_exact(&mut magic)?;
+        if magic != MAGIC_BYTES {
+            return Err(FramingError::InvalidIndex(
+                "missing magic bytes".to_string(),
+            ));
+        }
+        let version = read_u64(&mut data)?;
+        if version > VERSION as u64 {
+            return Err(FramingError::InvalidIndex(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+
+        let mut index = Self::default();
+        for _ in 0..read_u64(&mut data)? {
+            let bin = read_u64(&mut data)?;
+            let ranges = (0..read_u64(&mut data)?)
+                .map(|_| -> Result<_, FramingError> {
+                    let start = read_u64(&mut data)?.into();
+                    let end = read_u64(&mut data)?.into();
+                    Ok((start, end))
+                })
+                .collect::<Result<_, _>>()?;
+            index.bin_to_ranges.insert(bin, ranges);
+        }
+        index.window_to_start = (0..read_u64(&mut data)?)
+            .map(|_| read_u64(&mut data))
+            .collect::<Result<_, _>>()?;
+        Ok(index)
+    }
+
+    pub fn parse_from_file(path: impl AsRef<std::path::Path>) -> Result<Self, FramingError> {
+        let f = File::open(path)?;
+        Self::parse(f)
+    }
+
+    pub fn write(&self, out_file: impl Write) -> Result<(), FramingError> {
+        let mut buf = MAGIC_BYTES.to_vec();
+        encode_varint(VERSION as u64, &mut buf);
+        encode_varint(self.bin_to_ranges.len() as u64, &mut buf);
+        for (bin, ranges) in &self.bin_to_ranges {
+            encode_varint(*bin, &mut buf);
+            encode_varint(ranges.len() as u64, &mut buf);
+            for (start, end) in ranges {
+                encode_varint((*start).into(), &mut buf);
+                encode_varint((*end).into(), &mut buf);
+            }
+        }
+        encode_varint(self.window_to_start.len() as u64, &mut buf);
+        for start in &self.window_to_start {
+            encode_varint(*start, &mut buf);
+        }
+
+        let mut encoder = GzEncoder::new(out_file, Compression::default());
+        encoder.write_all(&buf)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<std::path::Path>) -> Result<(), FramingError> {
+        let f = File::create(path)?;
+        self.write(f)
+    }
+}
+
+/**
+ * Iterator over the alignments of an indexed GAM touching a range of nodes
+ */
+pub struct Query<'a, R: Read + Seek> {
+    reader: &'a mut gam::Reader<R>,
+    ranges: VecDeque<(VirtualOffset, VirtualOffset)>,
+    min_id: i64,
+    max_id: i64,
+    // End of the range being read
+    end: Option<VirtualOffset>,
+    buffer: VecDeque<vg::Alignment>,
+}
+
+impl<'a, R: Read + Seek> Query<'a, R> {
+    fn fill_buffer(&mut self) -> Result<bool, FramingError> {
+        while self.buffer.is_empty() {
+            let end = match self.end {
+                Some(end) if self.reader.virtual_position()? < end => end,
+                _ => {
+                    let Some((start, end)) = self.ranges.pop_front() else {
+                        return Ok(false);
+                    };
+                    self.reader.seek_virtual(start)?;
+                    self.end = Some(end);
+                    end
+                }
+            };
+            let Some(group) = self.reader.read_group()? else {
+                return Ok(false);
+            };
+            if self.reader.virtual_position()? >= end {
+                self.end = None;
+            }
+            self.buffer.extend(group.into_iter().filter(|alignment| {
+                alignment_nodes(alignment).any(|id| self.min_id <= id && id <= self.max_id)
+            }));
+        }
+        Ok(true)
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for Query<'a, R> {
+    type Item = Result<vg::Alignment, FramingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.fill_buffer() {
+            Ok(true) => self.buffer.pop_front().map(Ok),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn alignment_nodes(alignment: &vg::Alignment) -> impl Iterator<Item = i64> + '_ {
+    alignment
+        .path
+        .iter()
+        .flat_map(|path| &path.mapping)
+        .filter_map(|mapping| mapping.position.as_ref())
+        .map(|position| position.node_id)
+}
+
+/**
+ * Smallest bin of the trie containing both node IDs.
+ * Bins are numbered in breadth-first order: a bin at depth `d` with prefix `p` is `2^d - 1 + p`.
+ */
+fn common_bin(a: u64, b: u64) -> u64 {
+    // The deepest level only splits pairs of IDs, so that bin numbers fit in 64 bits
+    let depth = (a ^ b).leading_zeros().min(63);
+    if depth == 0 {
+        return 0;
+    }
+    (1 << depth) - 1 + (a >> (64 - depth))
+}
+
+/**
+ * Range of node IDs (inclusive) covered by a bin
+ */
+fn bin_range(bin: u64) -> (u64, u64) {
+    let depth = (bin + 1).ilog2();
+    if depth == 0 {
+        return (0, u64::MAX);
+    }
+    let prefix = bin + 1 - (1 << depth);
+    let shift = 64 - depth;
+    (prefix << shift, prefix << shift | (u64::MAX >> depth))
+}
+
+fn read_u64(data: &mut impl BufRead) -> Result<u64, FramingError> {
+    Ok(crate::framing::read_varint(data)?.ok_or(ErrorKind::UnexpectedEof)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bins() {
+        assert_eq!(common_bin(0, u64::MAX), 0);
+        for (a, b) in [
+            (1000, 1000),
+            (1000, 2000),
+            (1, 1 << 40),
+            (u64::MAX - 1, u64::MAX),
+        ] {
+            let (min, max) = bin_range(common_bin(a, b));
+            assert!(min <= a && b <= max);
+        }
+    }
+
+    #[test]
+    fn gai_format() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = GamIndex::default();
+        index.add_group(1, 2, VirtualOffset::new(0, 0), VirtualOffset::new(0, 100));
+        let mut buf = vec![];
+        index.write(&mut buf)?;
+
+        let mut data = vec![];
+        MultiGzDecoder::new(&buf[..]).read_to_end(&mut data)?;
+        let mut expected = b"GXI!".to_vec();
+        // Version, then a single bin holding a single range
+        for value in [1, 1, common_bin(1, 2), 1, 0, 100] {
+            encode_varint(value, &mut expected);
+        }
+        // A single window, starting at the first group
+        for value in [1, 0] {
+            encode_varint(value, &mut expected);
+        }
+        assert_eq!(data, expected);
+
+        // Indexes of `vg gamsort -i` are rejected
+        let mut vg_index = vec![];
+        let mut encoder = GzEncoder::new(&mut vg_index, Compression::default());
+        encoder.write_all(b"GAI!")?;
+        encoder.finish()?;
+        assert!(matches!(
+            GamIndex::parse(&vg_index[..]),
+            Err(FramingError::InvalidIndex(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn gai_query() -> Result<(), Box<dyn std::error::Error>> {
+        let index = GamIndex::index_file("data/example.gam")?;
+        let mut buf = vec![];
+        index.write(&mut buf)?;
+        assert_eq!(GamIndex::parse(&buf[..])?, index);
+
+        let (min_id, max_id) = (9000, 10000);
+        let expected: Vec<_> = gam::parse_from_file("data/example.gam")?
+            .into_iter()
+            .filter(|a| alignment_nodes(a).any(|id| min_id <= id && id <= max_id))
+            .collect();
+        assert!(!expected.is_empty());
+
+        let mut reader = gam::Reader::new(File::open("data/example.gam")?);
+        let found = index
+            .query(&mut reader, min_id, max_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(found, expected);
+        Ok(())
+    }
+}
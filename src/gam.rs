@@ -1,12 +1,23 @@
 use crate::{
-    framing::{self, vg, FramingError},
-    gaf::GafRecord,
-    graph::GFAExt,
-    ConversionError,
+    framing::{self, vg, Compression, FramingError},
+    gaf::{GafError, GafRecord, Location},
+    graph::{node_slice, GFAExt, GraphIndex},
+    nucleotide, ConversionError,
 };
-use gfa::gfa::GFA;
 use prost_types::{value::Kind, Struct, Value};
-use std::{collections::BTreeMap, fs::File, io::prelude::*};
+use std::{collections::BTreeMap, io::prelude::*};
+
+pub use crate::gamsort::{sort, sort_alignments, sort_file, SortOptions};
+
+/**
+ * Streaming GAM reader, yielding one `vg::Alignment` at a time
+ */
+pub type Reader<R> = framing::Reader<R, vg::Alignment>;
+
+/**
+ * Incremental GAM writer, accepting one `vg::Alignment` at a time
+ */
+pub type Writer<W> = framing::Writer<W, vg::Alignment>;
 
 pub fn parse(data: impl Read) -> Result<Vec<vg::Alignment>, FramingError> {
     framing::parse::<vg::Alignment>(data)
@@ -15,41 +26,113 @@ pub fn parse(data: impl Read) -> Result<Vec<vg::Alignment>, FramingError> {
 pub fn parse_from_file(
     path: impl AsRef<std::path::Path>,
 ) -> Result<Vec<vg::Alignment>, FramingError> {
-    let f = File::open(path)?;
+    let f = framing::open(path)?;
     parse(f)
 }
 
-pub fn write(alignments: &[vg::Alignment], mut out_file: impl Write) -> Result<(), FramingError> {
-    framing::write::<vg::Alignment>(alignments, &mut out_file)
+/**
+ * Write BGZF-compressed alignments, groups are encoded and deflated by `threads` worker threads
+ */
+pub fn write(
+    alignments: &[vg::Alignment],
+    out_file: impl Write,
+    threads: usize,
+) -> Result<(), FramingError> {
+    let mut writer = Writer::with_threads(out_file, Compression::default(), threads);
+    for alignment in alignments {
+        writer.write_record(alignment)?;
+    }
+    writer.finish()?;
+    Ok(())
 }
 
 pub fn write_to_file(
     alignments: &[vg::Alignment],
     path: impl AsRef<std::path::Path>,
+    threads: usize,
 ) -> Result<(), FramingError> {
-    let f = File::create(path)?;
-    write(alignments, f)
+    let mut f = framing::create(path)?;
+    write(alignments, &mut f, threads)?;
+    Ok(f.flush()?)
 }
 
+/**
+ * Complement of each base of `sequence`, without reversing it
+ */
+#[deprecated(note = "use `nucleotide::complement` or `nucleotide::reverse_complement`")]
 pub fn complement(sequence: String) -> String {
-    sequence.chars().map(complement_char).collect()
+    sequence.chars().map(nucleotide::complement).collect()
 }
 
+#[deprecated(note = "use `nucleotide::complement`")]
 pub fn complement_char(c: char) -> char {
-    match c {
-        'A' => 'T',
-        'T' => 'A',
-        'C' => 'G',
-        'G' => 'C',
-        _ => panic!("Invalid base: {}", c),
-    }
+    nucleotide::complement(c)
+}
+
+/**
+ * Number of bases of node `node_id` after `offset`
+ */
+fn remaining_length(
+    graph: &GraphIndex,
+    node_id: i64,
+    offset: i64,
+) -> Result<usize, ConversionError> {
+    let len = graph.node_to_length(node_id)?;
+    (len as i64)
+        .checked_sub(offset)
+        .filter(|remaining| *remaining >= 0 && offset >= 0)
+        .map(|remaining| remaining as usize)
+        .ok_or(ConversionError::OffsetOutOfRange {
+            node: node_id,
+            offset,
+            len,
+        })
+}
+
+/**
+ * Node of the mapping `index`, an error if the alignment goes past the end of the path
+ */
+fn mapping_node(
+    graph: &GraphIndex,
+    mapping: &[vg::Mapping],
+    index: usize,
+) -> Result<i64, ConversionError> {
+    let Some(current) = mapping.get(index) else {
+        let last = mapping
+            .last()
+            .and_then(|last| last.position.as_ref())
+            .ok_or(ConversionError::MissingPosition)?;
+        let len = graph.node_to_length(last.node_id)?;
+        return Err(ConversionError::OffsetOutOfRange {
+            node: last.node_id,
+            offset: len as i64,
+            len,
+        });
+    };
+    Ok(current
+        .position
+        .as_ref()
+        .ok_or(ConversionError::MissingPosition)?
+        .node_id)
 }
 
 impl vg::Alignment {
     pub fn convert_from_gaf(
         value: &GafRecord,
-        graph: &GFA<usize, ()>,
+        graph: &GraphIndex,
     ) -> Result<Self, ConversionError> {
+        // Steps on stable sequences are converted to the segments covering them,
+        // and the segments to the nodes of vg, the record is only copied if there is any
+        let resolved;
+        let value =
+            if value.path.iter().any(|step| step.is_stable) || !graph.translation().is_empty() {
+                resolved = value
+                    .resolve_stable_steps(graph)?
+                    .segments_to_nodes(graph)?;
+                &resolved
+            } else {
+                value
+            };
         let mut mapping = value
             .path
             .iter()
@@ -57,7 +140,7 @@ impl vg::Alignment {
             .map(|(rank, step)| -> Result<_, _> {
                 let offset = if rank == 0 { value.path_start } else { 0 };
                 let position = vg::Position {
-                    node_id: step.name.parse::<i64>()?,
+                    node_id: step.name.parse()?,
                     offset,
                     is_reverse: step.is_reverse,
                     ..Default::default()
@@ -77,20 +160,15 @@ impl vg::Alignment {
             let mut cur_mapping = 0;
             let mut cur_offset = value.path_start;
 
-            let mut cur_len = graph.node_to_length(
-                mapping[cur_mapping]
-                    .position
-                    .as_ref()
-                    .ok_or(ConversionError::MissingPosition)?
-                    .node_id,
-            );
+            let mut cur_len = graph.node_to_length(mapping_node(graph, &mapping, cur_mapping)?)?;
             let mut from_cg = false;
-            for cigar in value.iter_cigar() {
+            for cigar in value.iter_cigar()? {
                 if !from_cg
                     && cigar.cat != ':'
                     && cigar.cat != '+'
                     && cigar.cat != '-'
                     && cigar.cat != '*'
+                    && cigar.cat != '~'
                 {
                     from_cg = true;
                 }
@@ -98,15 +176,9 @@ impl vg::Alignment {
                     ':' | 'M' | '=' | 'X' => {
                         let mut match_len = cigar.length;
                         while match_len > 0 {
-                            let current_match = match_len.min(
-                                graph.node_to_length(
-                                    mapping[cur_mapping]
-                                        .position
-                                        .as_ref()
-                                        .ok_or(ConversionError::MissingPosition)?
-                                        .node_id,
-                                ) - cur_offset as usize,
-                            );
+                            let node_id = mapping_node(graph, &mapping, cur_mapping)?;
+                            let current_match =
+                                match_len.min(remaining_length(graph, node_id, cur_offset)?);
                             let mut edit_sequence = String::new();
                             if cigar.cat == 'X' {
                                 edit_sequence = "N".repeat(current_match);
@@ -115,9 +187,15 @@ impl vg::Alignment {
                                 .position
                                 .clone()
                                 .ok_or(ConversionError::MissingPosition)?;
-                            sequence += &graph
-                                .node_to_sequence(cur_position.node_id, cur_position.is_reverse)
-                                [cur_offset as usize..cur_offset as usize + current_match];
+                            sequence += node_slice(
+                                graph.node_to_sequence(
+                                    cur_position.node_id,
+                                    cur_position.is_reverse,
+                                )?,
+                                cur_position.node_id,
+                                cur_offset,
+                                cur_offset + current_match as i64,
+                            )?;
 
                             let edit = vg::Edit {
                                 from_length: current_match as i32,
@@ -130,11 +208,15 @@ impl vg::Alignment {
                             if match_len > 0 {
                                 cur_mapping += 1;
                                 cur_offset = 0;
-                                cur_len = graph.node_to_length(cur_position.node_id);
+                                cur_len = graph.node_to_length(mapping_node(
+                                    graph,
+                                    &mapping,
+                                    cur_mapping,
+                                )?)?;
                             }
                         }
                     }
-                    '+' | 'I' | 'S' => {
+                    '+' | 'I' => {
                         let mut target_mapping = cur_mapping;
                         if cur_offset == 0
                             && cur_mapping > 0
@@ -163,19 +245,12 @@ impl vg::Alignment {
 
                         mapping[target_mapping].edit.push(edit);
                     }
-                    '-' | 'D' => {
+                    '-' | 'D' | 'N' | '~' => {
                         let mut del_len = cigar.length;
                         while del_len > 0 {
-                            let current_del = del_len.min(
-                                graph.node_to_length(
-                                    mapping[cur_mapping]
-                                        .position
-                                        .as_ref()
-                                        .ok_or(ConversionError::MissingPosition)?
-                                        .node_id
-                                        - cur_offset,
-                                ),
-                            );
+                            let node_id = mapping_node(graph, &mapping, cur_mapping)?;
+                            let current_del =
+                                del_len.min(remaining_length(graph, node_id, cur_offset)?);
                             let edit = vg::Edit {
                                 from_length: current_del as i32,
                                 to_length: 0,
@@ -187,13 +262,11 @@ impl vg::Alignment {
                             if del_len > 0 {
                                 cur_mapping += 1;
                                 cur_offset = 0;
-                                cur_len = graph.node_to_length(
-                                    mapping[cur_mapping]
-                                        .position
-                                        .as_ref()
-                                        .ok_or(ConversionError::MissingPosition)?
-                                        .node_id,
-                                );
+                                cur_len = graph.node_to_length(mapping_node(
+                                    graph,
+                                    &mapping,
+                                    cur_mapping,
+                                )?)?;
                             }
                         }
                     }
@@ -204,22 +277,20 @@ impl vg::Alignment {
                             to_length: cigar.length as i32,
                             sequence: cigar.query,
                         };
+                        mapping_node(graph, &mapping, cur_mapping)?;
                         mapping[cur_mapping].edit.push(edit);
                         cur_offset += 1;
                     }
+                    // clipped bases are outside of the query interval, padding is not on the path
+                    'S' | 'H' | 'P' => (),
                     _ => unreachable!(),
                 }
                 if cur_offset == cur_len as i64 {
                     cur_mapping += 1;
                     cur_offset = 0;
                     if cur_mapping < mapping.len() {
-                        cur_len = graph.node_to_length(
-                            mapping[cur_mapping]
-                                .position
-                                .as_ref()
-                                .ok_or(ConversionError::MissingPosition)?
-                                .node_id,
-                        );
+                        cur_len =
+                            graph.node_to_length(mapping_node(graph, &mapping, cur_mapping)?)?;
                     }
                 }
             }
@@ -253,37 +324,46 @@ impl vg::Alignment {
 
         let mut alignment = Self {
             name,
-            sequence: complement(sequence),
+            // like in vg, the sequence is read along the path whatever the strand of the read
+            sequence,
             path: Some(path),
             mapping_quality: value.mapq,
             annotation,
             ..Default::default()
         };
 
-        for (key, value) in value.opt_fields.clone() {
+        for (key, field) in &value.opt_fields {
             match key.as_str() {
                 "dv" => {
                     // get the identity from the dv divergence field
-                    alignment.identity = 1.0 - value.1.parse::<f64>()?;
+                    alignment.identity = 1.0 - value.tag_f64(key)?.unwrap_or_default();
                 }
                 "AS" => {
                     // get the score from the AS field
-                    alignment.score = value.1.parse::<i32>()?;
+                    let score = value.tag_i64(key)?.unwrap_or_default();
+                    alignment.score = i32::try_from(score).map_err(|_| {
+                        GafError::InvalidTag(Location::new("tag", &format!("AS:i:{}", score)))
+                    })?;
                 }
                 "bq" => {
-                    // get the quality from the bq field
-                    todo!();
+                    // get the quality from the bq field, Phred+33 encoded
+                    alignment.quality = value
+                        .tag_str(key)?
+                        .unwrap_or_default()
+                        .bytes()
+                        .map(|byte| byte.saturating_sub(33))
+                        .collect();
                 }
                 "fp" => {
                     // get the fragment_previous field
                     if let Some(fragment) = alignment.fragment_prev.as_mut() {
-                        fragment.name = value.1;
+                        fragment.name = field.1.clone();
                     }
                 }
                 "fn" => {
                     // get the fragment_next field
                     if let Some(fragment) = alignment.fragment_next.as_mut() {
-                        fragment.name = value.1;
+                        fragment.name = field.1.clone();
                     }
                 }
                 "pd" => {
@@ -292,7 +372,7 @@ impl vg::Alignment {
                         annotations.fields.insert(
                             "proper_pair".to_string(),
                             Value {
-                                kind: Some(Kind::BoolValue(value.1 == "1")),
+                                kind: Some(Kind::BoolValue(value.tag_bool(key)? == Some(true))),
                             },
                         );
                     }
@@ -330,7 +410,7 @@ impl From<vg::MultipathAlignment> for vg::Alignment {
 mod tests {
     use super::*;
     use crate::{convert_gaf_to_gam, gaf};
-    use gfa::parser::GFAParser;
+    use gfa::{gfa::GFA, parser::GFAParser};
     use prost_types::{value::Kind, Value};
     use std::fs::File;
 
@@ -353,6 +433,173 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn gaf_stable_to_gam() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<usize, gfa::optfields::OptionalFields> =
+            GFAParser::new().parse_file("data/stable.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
+        let record = |path: &str, path_length: i64, path_start: i64| {
+            gaf::GafRecord::parse(&format!(
+                "read\t6\t0\t6\t+\t{}\t{}\t{}\t{}\t6\t6\t60\tcs:Z::6",
+                path,
+                path_length,
+                path_start,
+                path_start + 6
+            ))
+        };
+        for (stable_path, node_path, path_length, path_start) in [
+            (">chr1:2-8", ">1>2>3", 10, 2),
+            ("chr1:2-8", ">1>2>3", 10, 2),
+            ("<chr1:2-8", "<3<2<1", 10, 2),
+            (">chr1:0-4>alt1:0-1>chr1:6-7", ">1>4>3", 9, 0),
+        ] {
+            let stable = record(stable_path, 6, 0)?;
+            let nodes = record(node_path, path_length, path_start)?;
+            assert_eq!(stable.resolve_stable_steps(&graph)?, nodes);
+            assert_eq!(
+                vg::Alignment::convert_from_gaf(&stable, &graph)?,
+                vg::Alignment::convert_from_gaf(&nodes, &graph)?
+            );
+        }
+
+        // Reads are rebuilt from the reverse complement of segments traversed backwards,
+        // in the orientation of the path like the edits
+        for strand in ['+', '-'] {
+            let mut nodes = record("<3<2", 6, 0)?;
+            nodes.strand = strand;
+            let alignment = vg::Alignment::convert_from_gaf(&nodes, &graph)?;
+            assert_eq!(alignment.sequence, "GTAACC");
+            let mut edited = String::new();
+            for mapping in &alignment.path.as_ref().unwrap().mapping {
+                let position = mapping.position.as_ref().unwrap();
+                let sequence = graph.node_to_sequence(position.node_id, position.is_reverse)?;
+                let mut offset = position.offset;
+                for edit in &mapping.edit {
+                    let end = offset + edit.from_length as i64;
+                    if edit.is_match() {
+                        edited += node_slice(sequence, position.node_id, offset, end)?;
+                    } else {
+                        edited += &edit.sequence;
+                    }
+                    offset = end;
+                }
+            }
+            assert_eq!(edited, alignment.sequence);
+        }
+
+        // Nodes missing from the graph, and paths longer than their nodes
+        let missing = record(">1>9", 10, 2)?;
+        assert!(matches!(
+            vg::Alignment::convert_from_gaf(&missing, &graph),
+            Err(ConversionError::MissingNode(9))
+        ));
+        let too_long = record(">1", 4, 2)?;
+        assert!(matches!(
+            vg::Alignment::convert_from_gaf(&too_long, &graph),
+            Err(ConversionError::OffsetOutOfRange { node: 1, .. })
+        ));
+
+        // Unknown stable sequence, and interval starting inside a segment in the middle of the path
+        for stable_path in [">chr2:0-6", ">chr1:0-4>chr1:5-7"] {
+            let stable = record(stable_path, 6, 0)?;
+            assert!(stable.resolve_stable_steps(&graph).is_err());
+        }
+
+        // Matches running over a node boundary into a longer node
+        let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/boundary.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
+        for tag in ["cg:Z:8M1X", "cs:Z::8*ga"] {
+            let line = format!("read\t9\t0\t9\t+\t>1>2\t14\t0\t9\t8\t9\t60\t{}", tag);
+            let alignment =
+                vg::Alignment::convert_from_gaf(&gaf::GafRecord::parse(&line)?, &graph)?;
+            let path = alignment.path.expect("missing path");
+            let lengths: Vec<Vec<i32>> = path
+                .mapping
+                .iter()
+                .map(|m| m.edit.iter().map(|e| e.from_length).collect())
+                .collect();
+            assert_eq!(lengths, [vec![4], vec![4, 1]], "{}", tag);
+        }
+
+        // Soft-clipped bases are outside of the query interval, like hard-clipped ones
+        for (query_start, query_end, cg) in [(2, 6, "2S4M"), (0, 4, "4M2S"), (0, 4, "4M2H")] {
+            let line = format!(
+                "read\t6\t{}\t{}\t+\t>1\t4\t0\t4\t4\t4\t60\tcg:Z:{}",
+                query_start, query_end, cg
+            );
+            let record = gaf::GafRecord::parse(&line)?;
+            assert_eq!(record.iter_cg()?.len(), 2);
+            let alignment = vg::Alignment::convert_from_gaf(&record, &graph)?;
+            assert_eq!(alignment.sequence, "ACGT", "{}", cg);
+            let edits = &alignment.path.as_ref().unwrap().mapping[0].edit;
+            assert_eq!(edits.len(), 1, "{}", cg);
+            assert!(edits[0].is_match());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_quality() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/boundary.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
+        let line = "read\t4\t0\t4\t+\t>1\t4\t0\t3\t4\t4\t60\tcs:Z::4\tbq:Z:I5#!";
+        let record = gaf::GafRecord::parse(line)?;
+        let alignment = vg::Alignment::convert_from_gaf(&record, &graph)?;
+        assert_eq!(alignment.quality, [40, 20, 2, 0]);
+        let converted = gaf::GafRecord::convert_from_gam(&alignment, &graph)?;
+        assert_eq!(converted.tag_str("bq")?, Some("I5#!"));
+        Ok(())
+    }
+
+    #[test]
+    fn gaf_score() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/boundary.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
+        let line = "read\t4\t0\t4\t+\t>1\t4\t0\t3\t4\t4\t60\tcs:Z::4";
+        let record = gaf::GafRecord::parse(&format!("{}\tAS:i:-12", line))?;
+        assert_eq!(vg::Alignment::convert_from_gaf(&record, &graph)?.score, -12);
+
+        // Scores of vg are 32-bit, larger ones are not truncated
+        let record = gaf::GafRecord::parse(&format!("{}\tAS:i:{}", line, 1u64 << 32))?;
+        let e = vg::Alignment::convert_from_gaf(&record, &graph).unwrap_err();
+        assert!(matches!(e, ConversionError::Gaf(GafError::InvalidTag(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn gam_reader() -> Result<(), Box<dyn std::error::Error>> {
+        use flate2::read::MultiGzDecoder;
+        use prost::{encoding::decode_varint, Message};
+
+        // Decode the groups by hand: count, type tag, then length-prefixed messages
+        let mut raw = vec![];
+        MultiGzDecoder::new(File::open("data/example.gam")?).read_to_end(&mut raw)?;
+        let mut data = &raw[..];
+        let mut expected = vec![];
+        while !data.is_empty() {
+            let count = decode_varint(&mut data)?;
+            for i in 0..count {
+                let len = decode_varint(&mut data)? as usize;
+                let (message, rest) = data.split_at(len);
+                if i == 0 {
+                    assert_eq!(message, b"GAM");
+                } else {
+                    expected.push(vg::Alignment::decode(message)?);
+                }
+                data = rest;
+            }
+        }
+        assert!(!expected.is_empty());
+        assert_eq!(expected[0].name, "FBtr0342963_e_1536_X_294766");
+
+        let mut reader = Reader::new(File::open("data/example.gam")?);
+        for alignment in &expected {
+            assert_eq!(&reader.next().unwrap()?, alignment);
+        }
+        assert!(reader.next().is_none());
+        Ok(())
+    }
+
     #[test]
     fn gam_write() -> Result<(), Box<dyn std::error::Error>> {
         let out_file = "data/example.out.gam";
@@ -365,7 +612,7 @@ mod tests {
             ..Default::default()
         };
         let alignments: Vec<vg::Alignment> = vec![alignment.clone()];
-        write(&alignments, of)?;
+        write(&alignments, of, 1)?;
 
         let in_file = "data/example.out.gam";
         let f = File::open(in_file)?;
@@ -376,6 +623,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn gam_writer() -> Result<(), Box<dyn std::error::Error>> {
+        let alignments = parse_from_file("data/example.gam")?;
+        let mut writer = Writer::new(vec![]);
+        for alignment in &alignments {
+            writer.write_record(alignment)?;
+        }
+        let data = writer.finish()?;
+
+        assert_eq!(parse(&data[..])?, alignments);
+        Ok(())
+    }
+
+    #[test]
+    fn gam_write_threads() -> Result<(), Box<dyn std::error::Error>> {
+        let alignments = parse_from_file("data/example.gam")?;
+        let mut expected = vec![];
+        write(&alignments, &mut expected, 1)?;
+        let mut data = vec![];
+        write(&alignments, &mut data, 4)?;
+
+        // Groups encoded in the workers end up in the same blocks, in order
+        assert_eq!(data, expected);
+        assert_eq!(parse(&data[..])?, alignments);
+        Ok(())
+    }
+
     #[test]
     fn gam_edit() -> Result<(), Box<dyn std::error::Error>> {
         let in_file = "data/example.gam";
@@ -394,7 +668,7 @@ mod tests {
 
         let out_file = "data/example.out.gam";
         let of = File::create(out_file)?;
-        write(&(vec![alignment.clone()]), of)?;
+        write(&[alignment.clone()], of, 1)?;
 
         let in_file = "data/example.out.gam";
         let f = File::open(in_file)?;
@@ -409,6 +683,7 @@ mod tests {
     fn convert_from_gaf() -> Result<(), Box<dyn std::error::Error>> {
         use pretty_assertions::assert_eq;
         let graph: GFA<usize, ()> = GFAParser::new().parse_file("data/convert.gfa")?;
+        let graph = GraphIndex::new(&graph)?;
         let gam = parse_from_file("data/convert.gam")?;
         let gaf = gaf::parse_from_file("data/convert.gaf")?;
 
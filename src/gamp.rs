@@ -1,8 +1,15 @@
-use crate::framing::{self, vg, FramingError};
-use std::{
-    fs::File,
-    io::{Read, Write},
-};
+use crate::framing::{self, vg, Compression, FramingError};
+use std::io::{Read, Write};
+
+/**
+ * Streaming GAMP reader, yielding one `vg::MultipathAlignment` at a time
+ */
+pub type Reader<R> = framing::Reader<R, vg::MultipathAlignment>;
+
+/**
+ * Incremental GAMP writer, accepting one `vg::MultipathAlignment` at a time
+ */
+pub type Writer<W> = framing::Writer<W, vg::MultipathAlignment>;
 
 pub fn parse(data: impl Read) -> Result<Vec<vg::MultipathAlignment>, FramingError> {
     framing::parse::<vg::MultipathAlignment>(data)
@@ -11,20 +18,34 @@ pub fn parse(data: impl Read) -> Result<Vec<vg::MultipathAlignment>, FramingErro
 pub fn parse_from_file(
     path: impl AsRef<std::path::Path>,
 ) -> Result<Vec<vg::MultipathAlignment>, FramingError> {
-    let f = File::open(path)?;
+    let f = framing::open(path)?;
     parse(f)
 }
 
-pub fn write(alignments: &[vg::MultipathAlignment], mut out_file: impl Write) -> Result<(), FramingError> {
-    framing::write::<vg::MultipathAlignment>(alignments, &mut out_file)
+/**
+ * Write BGZF-compressed alignments, groups are encoded and deflated by `threads` worker threads
+ */
+pub fn write(
+    alignments: &[vg::MultipathAlignment],
+    out_file: impl Write,
+    threads: usize,
+) -> Result<(), FramingError> {
+    let mut writer = Writer::with_threads(out_file, Compression::default(), threads);
+    for alignment in alignments {
+        writer.write_record(alignment)?;
+    }
+    writer.finish()?;
+    Ok(())
 }
 
 pub fn write_to_file(
     alignments: &[vg::MultipathAlignment],
     path: impl AsRef<std::path::Path>,
+    threads: usize,
 ) -> Result<(), FramingError> {
-    let f = File::create(path)?;
-    write(alignments, f)
+    let mut f = framing::create(path)?;
+    write(alignments, &mut f, threads)?;
+    Ok(f.flush()?)
 }
 
 impl From<vg::Alignment> for vg::MultipathAlignment {
@@ -84,7 +105,7 @@ mod tests {
             ..Default::default()
         };
         let alignments: Vec<vg::MultipathAlignment> = vec![alignment.clone()];
-        write(&alignments, of).unwrap();
+        write(&alignments, of, 1).unwrap();
 
         let in_file = "data/example.out.gamp";
         let f = File::open(in_file).unwrap();
@@ -112,7 +133,7 @@ mod tests {
 
         let out_file = "data/example.out.gamp";
         let of = File::create(out_file).unwrap();
-        write(&(vec![alignment.clone()]), of).unwrap();
+        write(&[alignment.clone()], of, 1).unwrap();
 
         let in_file = "data/example.out.gamp";
         let f = File::open(in_file).unwrap();
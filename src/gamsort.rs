@@ -0,0 +1,211 @@
+use crate::{
+    framing::{self, vg, Compression, FramingError},
+    gam,
+};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{prelude::*, BufReader, BufWriter, SeekFrom},
+    path::PathBuf,
+};
+
+/**
+ * Options of the external-memory sort
+ */
+#[derive(Debug, Clone)]
+pub struct SortOptions {
+    // Maximum number of alignments kept in memory, larger inputs are spilled to sorted runs
+    pub max_records_in_memory: usize,
+    // Directory of the temporary sorted runs
+    pub temp_dir: PathBuf,
+    // Maximum number of runs merged at once, more runs are merged in several passes
+    pub max_merge_width: usize,
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        Self {
+            max_records_in_memory: 1_000_000,
+            temp_dir: std::env::temp_dir(),
+            max_merge_width: 512,
+        }
+    }
+}
+
+/**
+ * Key used to sort alignments, as `vg gamsort` does: the minimum position of the path,
+ * comparing node ID, then strand, then offset. Unmapped alignments come first.
+ */
+fn sort_key(alignment: &vg::Alignment) -> (i64, bool, i64) {
+    alignment
+        .path
+        .iter()
+        .flat_map(|path| &path.mapping)
+        .filter_map(|mapping| mapping.position.as_ref())
+        .map(|position| (position.node_id, position.is_reverse, position.offset))
+        .min()
+        .unwrap_or_default()
+}
+
+/**
+ * Sort alignments in memory by their minimum position, keeping the input order for ties
+ */
+pub fn sort_alignments(alignments: &mut [vg::Alignment]) {
+    alignments.sort_by_cached_key(sort_key);
+}
+
+/**
+ * Sort a GAM by node ID, spilling sorted runs to temporary files when the input does not
+ * fit in `options.max_records_in_memory` alignments and merging them in the output.
+ */
+pub fn sort(
+    data: impl Read,
+    out_file: impl Write,
+    options: &SortOptions,
+) -> Result<(), FramingError> {
+    let mut runs = vec![];
+    let mut buffer = vec![];
+    for alignment in gam::Reader::new(data) {
+        buffer.push(alignment?);
+        if buffer.len() >= options.max_records_in_memory {
+            runs.push(write_run(&mut buffer, options)?);
+        }
+    }
+
+    if runs.is_empty() {
+        sort_alignments(&mut buffer);
+        return gam::write(&buffer, out_file, 1);
+    }
+    if !buffer.is_empty() {
+        runs.push(write_run(&mut buffer, options)?);
+    }
+    // Like vg gamsort, merge in passes to keep the number of open files bounded
+    let width = options.max_merge_width.max(2);
+    while runs.len() > width {
+        let mut merged = Vec::with_capacity(runs.len().div_ceil(width));
+        let mut pending = runs.into_iter().peekable();
+        while pending.peek().is_some() {
+            let pass = pending.by_ref().take(width).collect();
+            merged.push(finish_run(merge_runs(pass, run_writer(options)?)?)?);
+        }
+        runs = merged;
+    }
+    merge_runs(runs, gam::Writer::new(out_file))?;
+    Ok(())
+}
+
+/**
+ * Sort the GAM `in_path` into `out_path`, `-` standing for the standard input or output
+ */
+pub fn sort_file(
+    in_path: impl AsRef<std::path::Path>,
+    out_path: impl AsRef<std::path::Path>,
+    options: &SortOptions,
+) -> Result<(), FramingError> {
+    let in_file = framing::open(in_path)?;
+    let mut out_file = framing::create(out_path)?;
+    sort(in_file, &mut out_file, options)?;
+    Ok(out_file.flush()?)
+}
+
+/**
+ * Writer of a sorted run, in an anonymous temporary file
+ */
+fn run_writer(options: &SortOptions) -> Result<gam::Writer<BufWriter<File>>, FramingError> {
+    let run = tempfile::tempfile_in(&options.temp_dir)?;
+    // Runs are read back only once, favor speed over size
+    Ok(framing::Writer::with_compression(
+        BufWriter::new(run),
+        Compression::Bgzf(1),
+    ))
+}
+
+/**
+ * Close a sorted run and rewind it to be read
+ */
+fn finish_run(run: BufWriter<File>) -> Result<File, FramingError> {
+    let mut run = run.into_inner().map_err(|e| e.into_error())?;
+    run.seek(SeekFrom::Start(0))?;
+    Ok(run)
+}
+
+/**
+ * Sort the buffered alignments and write them to an anonymous temporary file
+ */
+fn write_run(buffer: &mut Vec<vg::Alignment>, options: &SortOptions) -> Result<File, FramingError> {
+    sort_alignments(buffer);
+    let mut writer = run_writer(options)?;
+    for alignment in buffer.drain(..) {
+        writer.write_record(&alignment)?;
+    }
+    finish_run(writer.finish()?)
+}
+
+/**
+ * k-way merge of sorted runs, returning the underlying writer of the output
+ */
+fn merge_runs<W: Write>(runs: Vec<File>, mut writer: gam::Writer<W>) -> Result<W, FramingError> {
+    let mut readers: Vec<_> = runs
+        .into_iter()
+        .map(|run| gam::Reader::new(BufReader::new(run)))
+        .collect();
+    let mut heads = Vec::with_capacity(readers.len());
+    // Ties are broken by run index, which keeps the sort stable
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        let head = reader.next().transpose()?;
+        if let Some(alignment) = &head {
+            heap.push(Reverse((sort_key(alignment), i)));
+        }
+        heads.push(head);
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let alignment = heads[i].take().expect("run without head in the heap");
+        writer.write_record(&alignment)?;
+        heads[i] = readers[i].next().transpose()?;
+        if let Some(alignment) = &heads[i] {
+            heap.push(Reverse((sort_key(alignment), i)));
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamsort() -> Result<(), Box<dyn std::error::Error>> {
+        let mut expected = gam::parse_from_file("data/example.gam")?;
+        sort_alignments(&mut expected);
+        assert!(expected
+            .windows(2)
+            .all(|w| sort_key(&w[0]) <= sort_key(&w[1])));
+
+        let options = SortOptions {
+            max_records_in_memory: 1000,
+            ..Default::default()
+        };
+        let mut sorted = vec![];
+        sort(File::open("data/example.gam")?, &mut sorted, &options)?;
+        assert_eq!(gam::parse(&sorted[..])?, expected);
+
+        // 10 runs merged 3 at a time, in three passes
+        let options = SortOptions {
+            max_records_in_memory: expected.len().div_ceil(10),
+            max_merge_width: 3,
+            ..Default::default()
+        };
+        let mut sorted = vec![];
+        sort(File::open("data/example.gam")?, &mut sorted, &options)?;
+        assert_eq!(gam::parse(&sorted[..])?, expected);
+
+        let dir = tempfile::tempdir()?;
+        let out_file = dir.path().join("example.sorted.gam");
+        sort_file("data/example.gam", &out_file, &options)?;
+        assert_eq!(gam::parse_from_file(&out_file)?, expected);
+        Ok(())
+    }
+}
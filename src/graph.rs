@@ -1,33 +1,406 @@
-use gfa::gfa::GFA;
+use crate::{nucleotide::reverse_complement, translation::Translation, ConversionError};
+use gfa::{
+    gfa::{SegmentId, GFA},
+    optfields::{OptFieldVal, OptFields},
+};
+use std::collections::{HashMap, HashSet};
+
+/**
+ * Segment of an rGFA graph, located on a stable sequence by its SN and SO tags
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StableSegment {
+    pub node_id: i64,
+    pub offset: i64, // 0-based offset on the stable sequence (SO)
+    pub length: i64,
+}
 
 pub trait GFAExt {
-    fn node_to_length(&self, node_id: i64) -> usize;
-    fn node_to_sequence(&self, node_id: i64, is_reverse: bool) -> String;
+    fn node_to_length(&self, node_id: i64) -> Result<usize, ConversionError>;
+    fn node_to_sequence(&self, node_id: i64, is_reverse: bool) -> Result<&str, ConversionError>;
+    fn stable_segments(&self, name: &str) -> &[StableSegment];
+    fn segment_to_nodes(&self, name: &str) -> Result<Vec<i64>, ConversionError>;
+    fn segment_to_length(&self, name: &str) -> Result<usize, ConversionError>;
+    fn node_to_segment(&self, node_id: i64) -> String;
 }
 
-impl GFAExt for GFA<usize, ()> {
-    fn node_to_length(&self, node_id: i64) -> usize {
-        let node = self
-            .segments
-            .iter()
-            .find(|n| n.name == node_id as usize)
-            .unwrap();
-        node.sequence.len()
+/**
+ * Node lookup table of a GFA, built once and shared by the conversions of all the records
+ */
+#[derive(Debug, Clone, Default)]
+pub struct GraphIndex {
+    nodes: HashMap<i64, usize>, // node ID to its position in the sequence vectors
+    sequences: Vec<String>,
+    reverse_sequences: Vec<String>, // reverse complements, cached for nodes traversed backwards
+    stable: HashMap<String, Vec<StableSegment>>, // segments of each stable sequence, sorted by offset
+    translation: Translation,                    // empty if the segment names are the node IDs
+}
+
+impl GraphIndex {
+    /**
+     * Index the segments of `graph`.
+     * If some segment names are not numbers, all the segments get node IDs from 1 in the order
+     * of the GFA, and the translation between names and IDs is kept.
+     * Stable sequences are empty if the graph is not an rGFA or was loaded without optional fields.
+     */
+    pub fn new<N: SegmentId>(graph: &GFA<N, impl OptFields>) -> Result<Self, ConversionError> {
+        let names: Vec<_> = graph.segments.iter().map(|s| s.name.display()).collect();
+        let numeric: Option<Vec<i64>> = names.iter().map(|name| name.parse().ok()).collect();
+        let mut translation = Translation::default();
+        let node_ids = match numeric {
+            Some(node_ids) => node_ids,
+            None => (1..=names.len() as i64)
+                .zip(&names)
+                .map(|(node_id, name)| {
+                    if translation.nodes(name).is_some() {
+                        return Err(ConversionError::DuplicateSegment(name.clone()));
+                    }
+                    translation.insert(name, node_id)?;
+                    Ok(node_id)
+                })
+                .collect::<Result<_, ConversionError>>()?,
+        };
+        Self::build(graph, node_ids, translation)
     }
 
-    fn node_to_sequence(&self, node_id: i64, is_reverse: bool) -> String {
-        let node = self
-            .segments
+    /**
+     * Index the segments of `graph`, with the node IDs given by `translation`.
+     * The GFA either has the segment names of the translation, or the node IDs when it is
+     * the graph of vg where long segments are chopped into several nodes.
+     * The segment names are taken as node IDs only if all of them are nodes of the translation,
+     * so that the two kinds of names are never mixed.
+     */
+    pub fn with_translation<N: SegmentId>(
+        graph: &GFA<N, impl OptFields>,
+        translation: Translation,
+    ) -> Result<Self, ConversionError> {
+        let names: Vec<_> = graph.segments.iter().map(|s| s.name.display()).collect();
+        let nodes: Option<Vec<i64>> = names
             .iter()
-            .find(|n| n.name == node_id as usize)
-            .unwrap()
-            .sequence
-            .clone();
-        String::from_utf8(if is_reverse {
-            node.into_iter().rev().collect()
+            .map(|name| {
+                let node_id = name.parse().ok()?;
+                translation.name(node_id).map(|_| node_id)
+            })
+            .collect();
+        let node_ids = match nodes {
+            Some(node_ids) => node_ids,
+            None => names
+                .into_iter()
+                .map(|name| {
+                    translation
+                        .id(&name)
+                        .ok_or(ConversionError::MissingSegment(name))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Self::build(graph, node_ids, translation)
+    }
+
+    /**
+     * Translation between segment names and node IDs, empty if the names are the node IDs
+     */
+    pub fn translation(&self) -> &Translation {
+        &self.translation
+    }
+
+    /**
+     * Index the segments of `graph` under `node_ids`, an error if a segment or a node appears twice
+     */
+    fn build<N: SegmentId>(
+        graph: &GFA<N, impl OptFields>,
+        node_ids: Vec<i64>,
+        translation: Translation,
+    ) -> Result<Self, ConversionError> {
+        let mut index = Self {
+            translation,
+            ..Default::default()
+        };
+        let mut names = HashSet::new();
+        for (segment, node_id) in graph.segments.iter().zip(node_ids) {
+            let name = segment.name.display();
+            if !names.insert(name.clone()) {
+                return Err(ConversionError::DuplicateSegment(name));
+            }
+            if index.nodes.insert(node_id, index.sequences.len()).is_some() {
+                return Err(ConversionError::DuplicateNode(node_id));
+            }
+            let sequence = String::from_utf8_lossy(&segment.sequence).into_owned();
+            index.reverse_sequences.push(reverse_complement(&sequence));
+            index.sequences.push(sequence);
+
+            let Some(OptFieldVal::Z(stable_name)) =
+                segment.optional.get_field(b"SN").map(|field| &field.value)
+            else {
+                continue;
+            };
+            let Some(OptFieldVal::Int(offset)) =
+                segment.optional.get_field(b"SO").map(|field| &field.value)
+            else {
+                continue;
+            };
+            index
+                .stable
+                .entry(String::from_utf8_lossy(stable_name).into_owned())
+                .or_default()
+                .push(StableSegment {
+                    node_id,
+                    offset: *offset,
+                    length: segment.sequence.len() as i64,
+                });
+        }
+        for segments in index.stable.values_mut() {
+            segments.sort_by_key(|segment| segment.offset);
+        }
+        Ok(index)
+    }
+
+    fn position(&self, node_id: i64) -> Result<usize, ConversionError> {
+        self.nodes
+            .get(&node_id)
+            .copied()
+            .ok_or(ConversionError::MissingNode(node_id))
+    }
+}
+
+impl<T: OptFields> TryFrom<&GFA<usize, T>> for GraphIndex {
+    type Error = ConversionError;
+
+    fn try_from(graph: &GFA<usize, T>) -> Result<Self, Self::Error> {
+        Self::new(graph)
+    }
+}
+
+impl GFAExt for GraphIndex {
+    fn node_to_length(&self, node_id: i64) -> Result<usize, ConversionError> {
+        Ok(self.sequences[self.position(node_id)?].len())
+    }
+
+    fn node_to_sequence(&self, node_id: i64, is_reverse: bool) -> Result<&str, ConversionError> {
+        let position = self.position(node_id)?;
+        Ok(if is_reverse {
+            &self.reverse_sequences[position]
         } else {
-            node
+            &self.sequences[position]
         })
-        .unwrap()
+    }
+
+    /**
+     * Segments on the stable sequence `name`, sorted by offset
+     */
+    fn stable_segments(&self, name: &str) -> &[StableSegment] {
+        self.stable.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /**
+     * Node IDs of the segment `name` in order, through the translation if there is one
+     */
+    fn segment_to_nodes(&self, name: &str) -> Result<Vec<i64>, ConversionError> {
+        let nodes = if self.translation.is_empty() {
+            name.parse().ok().map(|node_id| vec![node_id])
+        } else {
+            self.translation.nodes(name).map(<[i64]>::to_vec)
+        };
+        nodes.ok_or_else(|| ConversionError::MissingSegment(name.to_string()))
+    }
+
+    fn segment_to_length(&self, name: &str) -> Result<usize, ConversionError> {
+        self.segment_to_nodes(name)?
+            .into_iter()
+            .map(|node_id| self.node_to_length(node_id))
+            .sum()
+    }
+
+    /**
+     * Name of the segment holding the node `node_id`, the ID itself if there is no translation
+     */
+    fn node_to_segment(&self, node_id: i64) -> String {
+        match self.translation.name(node_id) {
+            Some(name) => name.to_string(),
+            None => node_id.to_string(),
+        }
+    }
+}
+
+/**
+ * Bases `start..end` of the sequence of node `node_id`, an error if they are past its end
+ */
+pub(crate) fn node_slice(
+    sequence: &str,
+    node_id: i64,
+    start: i64,
+    end: i64,
+) -> Result<&str, ConversionError> {
+    let out_of_range = |offset| ConversionError::OffsetOutOfRange {
+        node: node_id,
+        offset,
+        len: sequence.len(),
+    };
+    if start < 0 || start > end {
+        return Err(out_of_range(start));
+    }
+    sequence
+        .get(start as usize..end as usize)
+        .ok_or_else(|| out_of_range(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gaf::GafRecord, vg};
+    use gfa::{optfields::OptionalFields, parser::GFAParser};
+
+    #[test]
+    fn graph_index() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<usize, OptionalFields> = GFAParser::new().parse_file("data/stable.gfa")?;
+        let index = GraphIndex::new(&graph)?;
+        assert_eq!(index.node_to_length(3)?, 4);
+        assert_eq!(index.node_to_sequence(3, false)?, "TTAC");
+        assert_eq!(index.node_to_sequence(3, true)?, "GTAA");
+        assert!(matches!(
+            index.node_to_length(5),
+            Err(ConversionError::MissingNode(5))
+        ));
+        assert_eq!(node_slice("TTAC", 3, 1, 3)?, "TA");
+        assert!(matches!(
+            node_slice("TTAC", 3, 2, 6),
+            Err(ConversionError::OffsetOutOfRange {
+                node: 3,
+                offset: 6,
+                len: 4
+            })
+        ));
+        let offsets: Vec<_> = index
+            .stable_segments("chr1")
+            .iter()
+            .map(|segment| (segment.node_id, segment.offset))
+            .collect();
+        assert_eq!(offsets, [(1, 0), (2, 4), (3, 6)]);
+        assert!(index.stable_segments("chr2").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn graph_names() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<Vec<u8>, OptionalFields> = GFAParser::new().parse_file("data/named.gfa")?;
+        let index = GraphIndex::new(&graph)?;
+        assert_eq!(index.translation().len(), 4);
+        assert_eq!(index.segment_to_nodes("s3")?, [3]);
+        assert_eq!(index.node_to_segment(3), "s3");
+        assert_eq!(index.node_to_sequence(3, false)?, "TTAC");
+        assert_eq!(index.stable_segments("chr1")[1].node_id, 2);
+        assert!(matches!(
+            index.segment_to_nodes("3"),
+            Err(ConversionError::MissingSegment(_))
+        ));
+
+        let line = "read\t6\t0\t6\t+\t>s1>s2>s3\t10\t2\t8\t6\t6\t60\tcs:Z::6";
+        let record = GafRecord::parse(line)?;
+        let alignment = vg::Alignment::convert_from_gaf(&record, &index)?;
+        let node_ids: Vec<_> = alignment
+            .path
+            .as_ref()
+            .unwrap()
+            .mapping
+            .iter()
+            .map(|mapping| mapping.position.as_ref().unwrap().node_id)
+            .collect();
+        assert_eq!(node_ids, [1, 2, 3]);
+        let converted = GafRecord::convert_from_gam(&alignment, &index)?;
+        assert_eq!(converted.path, record.path);
+
+        // The translation is saved and used again, numeric names are kept as IDs
+        let mut buf = vec![];
+        index.translation().write(&mut buf)?;
+        let translation = Translation::parse(&buf[..])?;
+        let reloaded = GraphIndex::with_translation(&graph, translation)?;
+        assert_eq!(reloaded.segment_to_nodes("s4")?, [4]);
+        let numeric: GFA<Vec<u8>, ()> = GFAParser::new().parse_file("data/stable.gfa")?;
+        assert!(GraphIndex::new(&numeric)?.translation().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn graph_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+        let mut named: GFA<Vec<u8>, ()> = GFAParser::new().parse_file("data/named.gfa")?;
+        named.segments.push(named.segments[0].clone());
+        assert!(matches!(
+            GraphIndex::new(&named),
+            Err(ConversionError::DuplicateSegment(name)) if name == "s1"
+        ));
+
+        let mut numeric: GFA<Vec<u8>, ()> = GFAParser::new().parse_file("data/stable.gfa")?;
+        numeric.segments.push(numeric.segments[0].clone());
+        assert!(matches!(
+            GraphIndex::new(&numeric),
+            Err(ConversionError::DuplicateSegment(name)) if name == "1"
+        ));
+        // Different names for the same node ID
+        numeric.segments.last_mut().unwrap().name = b"01".to_vec();
+        assert!(matches!(
+            GraphIndex::new(&numeric),
+            Err(ConversionError::DuplicateNode(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn graph_chopped() -> Result<(), Box<dyn std::error::Error>> {
+        let graph: GFA<Vec<u8>, ()> = GFAParser::new().parse_file("data/chopped.gfa")?;
+        let translation = Translation::parse_from_file("data/chopped.trans")?;
+        let index = GraphIndex::with_translation(&graph, translation)?;
+        assert_eq!(index.segment_to_nodes("s3")?, [4, 5]);
+        assert_eq!(index.segment_to_length("s3")?, 4);
+        assert_eq!(index.node_to_segment(5), "s3");
+
+        // Numeric segment names that are also node IDs are all read as node IDs
+        let data = "T\t1\t1,2\nT\t2\t3\nT\t3\t4,5\nT\t4\t6\n";
+        let numeric = GraphIndex::with_translation(&graph, Translation::parse(data.as_bytes())?)?;
+        assert_eq!(numeric.node_to_sequence(2, false)?, "GT");
+        assert_eq!(numeric.node_to_sequence(3, false)?, "GG");
+        assert_eq!(numeric.segment_to_nodes("2")?, [3]);
+
+        // Back on the segments, the path stops at the last node of the alignment
+        for (path, path_start, nodes, node_start, converted) in [
+            (">s1>s2>s3", 1, vec![1, 2, 3], 1, ">s1>s2\t6\t1\t3"),
+            (">s1>s2>s3", 3, vec![2, 3], 1, ">s1>s2\t6\t3\t5"),
+            ("<s3<s2<s1", 2, vec![4, 3], 0, "<s3<s2\t6\t2\t4"),
+            (">s1>s4>s3", 2, vec![2, 6], 0, ">s1>s4\t5\t2\t4"),
+        ] {
+            let line = format!(
+                "read\t3\t0\t3\t+\t{}\t9\t{}\t{}\t3\t3\t60\tcs:Z::3",
+                path,
+                path_start,
+                path_start + 3
+            );
+            let record = GafRecord::parse(&line)?;
+            let alignment = vg::Alignment::convert_from_gaf(&record, &index)?;
+            let mapping = &alignment.path.as_ref().unwrap().mapping;
+            let node_ids: Vec<_> = mapping
+                .iter()
+                .map(|mapping| mapping.position.as_ref().unwrap().node_id)
+                .collect();
+            assert_eq!(node_ids, nodes, "{}", path);
+            assert_eq!(mapping[0].position.as_ref().unwrap().offset, node_start);
+
+            let expected = format!("read\t3\t0\t3\t+\t{}\t3\t3\t60\tcs:Z::3", converted);
+            let converted = GafRecord::convert_from_gam(&alignment, &index)?;
+            assert_eq!(converted, GafRecord::parse(&expected)?, "{}", path);
+            assert_eq!(
+                vg::Alignment::convert_from_gaf(&converted, &index)?,
+                alignment,
+                "{}",
+                path
+            );
+        }
+
+        // A path leaving a chopped segment in the middle has no segment coordinates
+        let record = GafRecord::parse("read\t3\t0\t3\t+\t>s1>s2\t6\t1\t4\t3\t3\t60\tcs:Z::3")?;
+        let mut alignment = vg::Alignment::convert_from_gaf(&record, &index)?;
+        alignment.path.as_mut().unwrap().mapping.remove(1);
+        assert!(matches!(
+            GafRecord::convert_from_gam(&alignment, &index),
+            Err(ConversionError::IncompleteSegment(name)) if name == "s1"
+        ));
+        Ok(())
     }
 }
@@ -1,11 +1,21 @@
+pub mod framing;
 pub mod gaf;
+pub mod gai;
 pub mod gam;
 pub mod gamp;
+pub mod nucleotide;
 pub use framing::vg;
+pub use graph::{GFAExt, GraphIndex, StableSegment};
+pub use translation::{Translation, TranslationError};
 
+mod bgzf;
 mod bindings;
-mod framing;
+mod cs;
+mod gafindex;
+mod gaftag;
+mod gamsort;
 mod graph;
+mod translation;
 
 impl vg::Edit {
     pub fn is_match(&self) -> bool {
@@ -34,16 +44,35 @@ impl vg::Edit {
 pub enum ConversionError {
     ParseInt(#[from] std::num::ParseIntError),
     ParseFloat(#[from] std::num::ParseFloatError),
+    Gaf(#[from] gaf::GafError),
+    Translation(#[from] TranslationError),
     #[error("Missing position in mapping")]
     MissingPosition,
+    #[error("Cannot resolve stable step {0} in the graph")]
+    UnresolvedStableStep(String),
+    #[error("Node {0} is not in the graph")]
+    MissingNode(i64),
+    #[error("Segment {0} is not in the graph")]
+    MissingSegment(String),
+    #[error("Segment {0} appears twice in the graph")]
+    DuplicateSegment(String),
+    #[error("Node {0} appears twice in the graph")]
+    DuplicateNode(i64),
+    #[error("Path enters or leaves segment {0} in the middle")]
+    IncompleteSegment(String),
+    #[error("Offset {offset} is out of node {node} of length {len}")]
+    OffsetOutOfRange {
+        node: i64,
+        offset: i64,
+        len: usize,
+    },
 }
 
 use gaf::GafRecord;
-use gfa::gfa::GFA;
 
 pub fn convert_gam_to_gaf(
     value: &[vg::Alignment],
-    graph: &GFA<usize, ()>,
+    graph: &GraphIndex,
 ) -> Result<Vec<GafRecord>, ConversionError> {
     value
         .iter()
@@ -53,7 +82,7 @@ pub fn convert_gam_to_gaf(
 
 pub fn convert_gaf_to_gam(
     value: &[GafRecord],
-    graph: &GFA<usize, ()>,
+    graph: &GraphIndex,
 ) -> Result<Vec<vg::Alignment>, ConversionError> {
     value
         .iter()
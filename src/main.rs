@@ -15,13 +15,13 @@ fn main_() -> Result<(), Box<dyn std::error::Error>> {
 
     // GAM I/O
     let gam = gam::parse_from_file("data/example.gam")?;
-    gam::write_to_file(&gam, "data/example.out.gam")?;
+    gam::write_to_file(&gam, "data/example.out.gam", 1)?;
     assert!(gam == gam::parse_from_file("data/example.out.gam")?);
     println!("GAM: {} records", gam.len());
 
     // GAMP I/O
     let gamp = gamp::parse_from_file("data/example.gamp")?;
-    gamp::write_to_file(&gamp, "data/example.out.gamp")?;
+    gamp::write_to_file(&gamp, "data/example.out.gamp", 1)?;
     assert!(gamp == gamp::parse_from_file("data/example.out.gamp")?);
     println!("GAMP: {} records", gamp.len());
     Ok(())
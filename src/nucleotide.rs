@@ -0,0 +1,49 @@
+/**
+ * Complement of a base, IUPAC ambiguity codes included.
+ * The case is kept, and characters that are not bases (gaps, padding) are left as they are.
+ */
+pub fn complement(base: char) -> char {
+    let complement = match base.to_ascii_uppercase() {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' | 'U' => 'A',
+        'R' => 'Y',       // A or G
+        'Y' => 'R',       // C or T
+        'K' => 'M',       // G or T
+        'M' => 'K',       // A or C
+        'B' => 'V',       // not A
+        'V' => 'B',       // not T
+        'D' => 'H',       // not C
+        'H' => 'D',       // not G
+        _ => return base, // N, S and W are their own complement
+    };
+    if base.is_ascii_lowercase() {
+        complement.to_ascii_lowercase()
+    } else {
+        complement
+    }
+}
+
+/**
+ * Sequence of the other strand, read in the same direction
+ */
+pub fn reverse_complement(sequence: &str) -> String {
+    sequence.chars().rev().map(complement).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_complements() {
+        assert_eq!(reverse_complement("ACGT"), "ACGT");
+        assert_eq!(reverse_complement("aaCGn"), "nCGtt");
+        assert_eq!(reverse_complement("RYKMBVDHSWN-"), "-NWSDHBVKMRY");
+        assert_eq!(reverse_complement("u"), "a");
+        for base in "ACGTRYKMBVDHSWNacgtrykmbvdhswn".chars() {
+            assert_eq!(complement(complement(base)), base);
+        }
+    }
+}
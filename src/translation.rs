@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+};
+
+#[derive(thiserror::Error, Debug)]
+#[error(transparent)]
+pub enum TranslationError {
+    Io(#[from] std::io::Error),
+    #[error("Invalid translation line: {0}")]
+    InvalidLine(String),
+    #[error("Segment {0} is translated twice")]
+    DuplicateSegment(String),
+    #[error("Node {0} is translated twice")]
+    DuplicateNode(i64),
+}
+
+/**
+ * Translation between the GFA segment names and the numeric node IDs of vg, like the one
+ * written by `vg gbwt --translation`: one `T <segment name> <node IDs>` line per segment.
+ * A segment longer than the maximum node length of vg is chopped into several nodes,
+ * listed in order and separated by commas.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Translation {
+    segments: HashMap<String, Vec<i64>>, // nodes of each segment, in the segment orientation
+    nodes: HashMap<i64, (String, usize)>, // segment of each node and its rank in the segment
+}
+
+impl Translation {
+    /**
+     * Add `node_id` at the end of the segment `name`, an error if the node is already translated
+     */
+    pub fn insert(&mut self, name: &str, node_id: i64) -> Result<(), TranslationError> {
+        if self.nodes.contains_key(&node_id) {
+            return Err(TranslationError::DuplicateNode(node_id));
+        }
+        let nodes = self.segments.entry(name.to_string()).or_default();
+        self.nodes.insert(node_id, (name.to_string(), nodes.len()));
+        nodes.push(node_id);
+        Ok(())
+    }
+
+    /**
+     * Node ID of the segment `name`, if it was not chopped
+     */
+    pub fn id(&self, name: &str) -> Option<i64> {
+        match self.segments.get(name)?.as_slice() {
+            [node_id] => Some(*node_id),
+            _ => None,
+        }
+    }
+
+    /**
+     * Node IDs of the segment `name`, in order
+     */
+    pub fn nodes(&self, name: &str) -> Option<&[i64]> {
+        self.segments.get(name).map(Vec::as_slice)
+    }
+
+    /**
+     * Segment name of the node `node_id`
+     */
+    pub fn name(&self, node_id: i64) -> Option<&str> {
+        self.segment(node_id).map(|(name, _)| name)
+    }
+
+    /**
+     * Segment name of the node `node_id` and the rank of the node in the segment
+     */
+    pub fn segment(&self, node_id: i64) -> Option<(&str, usize)> {
+        self.nodes
+            .get(&node_id)
+            .map(|(name, rank)| (name.as_str(), *rank))
+    }
+
+    /**
+     * Number of segments
+     */
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /**
+     * Check if some segments are chopped into several nodes
+     */
+    pub fn is_chopped(&self) -> bool {
+        self.nodes.len() > self.segments.len()
+    }
+
+    pub fn parse(data: impl Read) -> Result<Self, TranslationError> {
+        let mut translation = Self::default();
+        for line in BufReader::new(data).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || TranslationError::InvalidLine(line.clone());
+            let mut fields = line.split('\t');
+            let (Some("T"), Some(name), Some(node_ids), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            if translation.segments.contains_key(name) {
+                return Err(TranslationError::DuplicateSegment(name.to_string()));
+            }
+            for node_id in node_ids.split(',') {
+                translation.insert(name, node_id.parse().map_err(|_| invalid())?)?;
+            }
+        }
+        Ok(translation)
+    }
+
+    pub fn parse_from_file(path: impl AsRef<std::path::Path>) -> Result<Self, TranslationError> {
+        let f = File::open(path)?;
+        Self::parse(f)
+    }
+
+    /**
+     * Write the translation sorted by node ID
+     */
+    pub fn write(&self, out_file: impl Write) -> Result<(), TranslationError> {
+        let mut out = BufWriter::new(out_file);
+        let mut segments: Vec<_> = self.segments.iter().collect();
+        segments.sort_by_key(|(_, nodes)| nodes.first().copied());
+        for (name, nodes) in segments {
+            let node_ids: Vec<_> = nodes.iter().map(i64::to_string).collect();
+            writeln!(out, "T\t{}\t{}", name, node_ids.join(","))?;
+        }
+        Ok(out.flush()?)
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<std::path::Path>) -> Result<(), TranslationError> {
+        let f = File::create(path)?;
+        self.write(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translation_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let data = "T\ts1\t1\nT\tchr1.seg5\t2,3,4\n";
+        let translation = Translation::parse(data.as_bytes())?;
+        assert_eq!(translation.id("s1"), Some(1));
+        assert_eq!(translation.id("chr1.seg5"), None);
+        assert_eq!(translation.nodes("chr1.seg5"), Some(&[2, 3, 4][..]));
+        assert_eq!(translation.segment(3), Some(("chr1.seg5", 1)));
+        assert_eq!(translation.name(1), Some("s1"));
+        assert_eq!(translation.id("s3"), None);
+        assert!(translation.is_chopped());
+
+        let mut buf = vec![];
+        translation.write(&mut buf)?;
+        assert_eq!(buf, data.as_bytes());
+
+        for bad in [
+            "S\ts1\t1",
+            "T\ts1",
+            "T\ts1\tx",
+            "T\ts1\t1,",
+            "T\ts1\t1\nT\ts1\t2",
+        ] {
+            assert!(Translation::parse(bad.as_bytes()).is_err());
+        }
+        for bad in ["T\ts1\t1\nT\ts2\t1", "T\ts1\t2,2"] {
+            assert!(matches!(
+                Translation::parse(bad.as_bytes()),
+                Err(TranslationError::DuplicateNode(_))
+            ));
+        }
+        Ok(())
+    }
+}